@@ -13,6 +13,9 @@ use crate::v1::agent_client::AgentClient;
 #[allow(clippy::all)]
 pub mod v1;
 
+/// Encoded file descriptor set of the agent API, served through gRPC reflection.
+pub const FILE_DESCRIPTOR_SET: &[u8] = include_bytes!("agent_descriptor.bin");
+
 pub fn select_channel(path: String) -> Option<Channel> {
     let address = Endpoint::try_from(format!("unix:/{path}"));
     if let Err(e) = address {
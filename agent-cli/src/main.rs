@@ -5,11 +5,15 @@ mod args;
 mod get;
 mod list;
 mod load;
+mod map;
 mod table;
 mod unload;
 mod utils;
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    AgentCli::parse().execute().await
+async fn main() {
+    if let Err(e) = AgentCli::parse().execute().await {
+        eprintln!("Error: {}", utils::format_error(&e));
+        std::process::exit(1);
+    }
 }
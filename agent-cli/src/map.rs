@@ -0,0 +1,56 @@
+use clap::{Args, Subcommand};
+use comfy_table::{Cell, Table};
+use tonic::transport::Channel;
+
+use agent_api::v1::agent_client::AgentClient;
+use agent_api::v1::DumpMapRequest;
+
+#[derive(Subcommand, Debug)]
+pub(crate) enum MapCommand {
+    /// Dump the entries of an eBPF map bound to a loaded program.
+    Dump(DumpMapArgs),
+}
+
+impl MapCommand {
+    pub(crate) async fn execute(&self, agent_client: AgentClient<Channel>) -> anyhow::Result<()> {
+        match self {
+            MapCommand::Dump(d) => execute_dump(agent_client, d).await,
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub(crate) struct DumpMapArgs {
+    /// Required: The name of the program the map is bound to.
+    pub(crate) program: String,
+
+    /// Required: The name of the eBPF map to dump.
+    pub(crate) map: String,
+
+    /// Optional: The maximum number of entries to dump.
+    /// Example: --limit 100
+    #[clap(short, long, verbatim_doc_comment)]
+    pub(crate) limit: Option<u32>,
+}
+
+async fn execute_dump(mut client: AgentClient<Channel>, args: &DumpMapArgs) -> anyhow::Result<()> {
+    let request = tonic::Request::new(DumpMapRequest {
+        program: args.program.clone(),
+        map: args.map.clone(),
+        limit: args.limit,
+    });
+    let response = client.dump_map(request).await?.into_inner();
+
+    let mut table = Table::new();
+    table.load_preset(comfy_table::presets::NOTHING);
+    table.set_header(vec![
+        Cell::new("Key").add_attribute(comfy_table::Attribute::Bold),
+        Cell::new("Value").add_attribute(comfy_table::Attribute::Bold),
+    ]);
+    for entry in response.entries.iter() {
+        table.add_row(vec![entry.key.clone(), entry.value.clone()]);
+    }
+    println!("{table}\n");
+    println!("{} entries", response.entries.len());
+    Ok(())
+}
@@ -1,5 +1,42 @@
+use tonic::Status;
+use tonic_types::StatusExt;
+
 /// Parse a single key-value pair
 pub(crate) fn parse_key_val(s: &str) -> Result<(String, String), std::io::Error> {
     let pos = s.find('=').ok_or(std::io::ErrorKind::InvalidInput)?;
     Ok((s[..pos].to_string(), s[pos + 1..].to_string()))
 }
+
+/// Formats an error for the terminal. Errors returned by the agent are reduced to their
+/// message and the details explaining it.
+pub(crate) fn format_error(e: &anyhow::Error) -> String {
+    let status = match e.downcast_ref::<Status>() {
+        Some(status) => status,
+        None => return format!("{:#}", e),
+    };
+    let mut message = status.message().to_string();
+    let details = status.get_error_details();
+    if let Some(bad_request) = details.bad_request() {
+        for violation in bad_request.field_violations.iter() {
+            message.push_str(&format!(
+                "\n  {}: {}",
+                violation.field, violation.description
+            ));
+        }
+    }
+    if let Some(failure) = details.precondition_failure() {
+        for violation in failure.violations.iter() {
+            message.push_str(&format!(
+                "\n  {}: {}",
+                violation.subject, violation.description
+            ));
+        }
+    }
+    if let Some(retry_delay) = details.retry_info().and_then(|info| info.retry_delay) {
+        message.push_str(&format!(
+            "\n  The agent may recover, retry in {}s",
+            retry_delay.as_secs()
+        ));
+    }
+    message
+}
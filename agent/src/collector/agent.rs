@@ -0,0 +1,232 @@
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+use parking_lot::Mutex;
+use prometheus_client::metrics::histogram::exponential_buckets;
+use prometheus_client::registry::Unit;
+use tonic::Status;
+
+use crate::managers::metrics::{HistogramValue, MetricFamily, MetricKind, Sample};
+
+lazy_static! {
+    /// Metrics about the agent itself, recorded by the managers, programs and servers.
+    pub(crate) static ref AGENT_METRICS: AgentMetrics = AgentMetrics::default();
+}
+
+/// A counter family, by the values of its labels in the order of `labels`.
+#[derive(Debug)]
+struct CounterFamily {
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl CounterFamily {
+    fn new(labels: &'static [&'static str]) -> Self {
+        Self {
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn inc_by(&self, values: &[&str], v: u64) {
+        let values = values.iter().map(|value| value.to_string()).collect();
+        *self.values.lock().entry(values).or_default() += v;
+    }
+
+    fn family(&self, name: &str, help: &str) -> MetricFamily {
+        let mut family = MetricFamily::new(name, help, None, MetricKind::Counter);
+        for (values, value) in self.values.lock().iter() {
+            family.samples.push(Sample::new(
+                self.labels.iter().copied().zip(values.iter().cloned()),
+                *value as f64,
+            ));
+        }
+        family
+    }
+}
+
+/// A histogram family of durations in seconds, by the values of its labels in the order
+/// of `labels`.
+#[derive(Debug)]
+struct DurationFamily {
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, HistogramValue>>,
+}
+
+impl DurationFamily {
+    fn new(labels: &'static [&'static str]) -> Self {
+        Self {
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    fn observe(&self, values: &[&str], elapsed: Duration) {
+        let values = values.iter().map(|value| value.to_string()).collect();
+        self.values
+            .lock()
+            .entry(values)
+            .or_insert_with(duration_histogram)
+            .observe(elapsed.as_secs_f64());
+    }
+
+    fn family(&self, name: &str, help: &str) -> MetricFamily {
+        let mut family = MetricFamily::new(name, help, Some(Unit::Seconds), MetricKind::Histogram);
+        for (values, histogram) in self.values.lock().iter() {
+            family.samples.push(Sample::histogram(
+                self.labels.iter().copied().zip(values.iter().cloned()),
+                histogram.clone(),
+            ));
+        }
+        family
+    }
+}
+
+/// Buckets from 1ms to ~32s, which covers both map polls and RPCs that call bpfman.
+fn duration_histogram() -> HistogramValue {
+    HistogramValue::new(&exponential_buckets(0.001, 2.0, 16).collect::<Vec<_>>())
+}
+
+#[derive(Debug)]
+pub(crate) struct AgentMetrics {
+    started_programs: Mutex<HashSet<String>>,
+    program_restarts: CounterFamily,
+    collect_duration: DurationFamily,
+    collect_errors: CounterFamily,
+    dropped_series: CounterFamily,
+    poll_duration: DurationFamily,
+    watch_restarts: CounterFamily,
+    rpc_requests: CounterFamily,
+    rpc_duration: DurationFamily,
+    bpfman_errors: CounterFamily,
+}
+
+impl Default for AgentMetrics {
+    fn default() -> Self {
+        Self {
+            started_programs: Mutex::new(HashSet::new()),
+            program_restarts: CounterFamily::new(&["program"]),
+            collect_duration: DurationFamily::new(&["program"]),
+            collect_errors: CounterFamily::new(&["program"]),
+            dropped_series: CounterFamily::new(&["program"]),
+            poll_duration: DurationFamily::new(&["program"]),
+            watch_restarts: CounterFamily::new(&["kind"]),
+            rpc_requests: CounterFamily::new(&["method", "code"]),
+            rpc_duration: DurationFamily::new(&["method"]),
+            bpfman_errors: CounterFamily::new(&["method"]),
+        }
+    }
+}
+
+impl AgentMetrics {
+    /// Records that the task of a program was started. Every start after the first one
+    /// counts as a restart.
+    pub(crate) fn record_program_start(&self, program: &str) {
+        if !self.started_programs.lock().insert(program.to_string()) {
+            self.program_restarts.inc_by(&[program], 1);
+        }
+    }
+
+    pub(crate) fn record_collect(&self, program: &str, elapsed: Duration, failed: bool) {
+        self.collect_duration.observe(&[program], elapsed);
+        if failed {
+            self.collect_errors.inc_by(&[program], 1);
+        }
+    }
+
+    pub(crate) fn record_series_dropped(&self, program: &str, series: usize) {
+        self.dropped_series.inc_by(&[program], series as u64);
+    }
+
+    pub(crate) fn record_poll(&self, program: &str, elapsed: Duration) {
+        self.poll_duration.observe(&[program], elapsed);
+    }
+
+    pub(crate) fn record_watch_restart(&self, kind: &str) {
+        self.watch_restarts.inc_by(&[kind], 1);
+    }
+
+    pub(crate) fn record_rpc<T>(
+        &self,
+        method: &str,
+        elapsed: Duration,
+        result: &Result<T, Status>,
+    ) {
+        let code = match result {
+            Ok(_) => tonic::Code::Ok,
+            Err(status) => status.code(),
+        };
+        self.rpc_requests
+            .inc_by(&[method, &format!("{:?}", code)], 1);
+        self.rpc_duration.observe(&[method], elapsed);
+    }
+
+    pub(crate) fn record_bpfman_error(&self, method: &str) {
+        self.bpfman_errors.inc_by(&[method], 1);
+    }
+
+    /// Returns the metric families of the agent, served on `/metrics` and pushed by the
+    /// exporters alike.
+    pub(crate) fn families(&self) -> Vec<MetricFamily> {
+        vec![
+            self.program_restarts.family(
+                "agent_program_restarts",
+                "number of times a program was started again after its first start",
+            ),
+            self.collect_duration.family(
+                "agent_program_collect_duration",
+                "time taken by a program to collect its metrics per poll",
+            ),
+            self.collect_errors.family(
+                "agent_program_collect_errors",
+                "number of polls in which a program failed to collect its metrics",
+            ),
+            self.dropped_series.family(
+                "agent_program_dropped_series",
+                "number of series of a program removed to stay within its cardinality budget",
+            ),
+            self.poll_duration.family(
+                "agent_program_poll_duration",
+                "time taken by a program to poll its eBPF maps",
+            ),
+            self.watch_restarts.family(
+                "agent_kube_watch_restarts",
+                "number of times a Kubernetes watch failed and was restarted",
+            ),
+            self.rpc_requests.family(
+                "agent_rpc_requests",
+                "number of agent gRPC requests handled",
+            ),
+            self.rpc_duration.family(
+                "agent_rpc_duration",
+                "time taken to handle an agent gRPC request",
+            ),
+            self.bpfman_errors
+                .family("agent_bpfman_errors", "number of failed calls to bpfman"),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_program_restarts_skip_first_start() {
+        let metrics = AgentMetrics::default();
+        let restarts = |metrics: &AgentMetrics| {
+            metrics
+                .program_restarts
+                .family("agent_program_restarts", "")
+                .samples
+                .iter()
+                .map(|sample| sample.value)
+                .sum::<f64>()
+        };
+        metrics.record_program_start("service-map");
+        assert_eq!(restarts(&metrics), 0.0);
+        metrics.record_program_start("service-map");
+        assert_eq!(restarts(&metrics), 1.0);
+    }
+}
@@ -1,38 +1,167 @@
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 
 use prometheus_client::collector::Collector as PrometheusCollector;
 use prometheus_client::encoding::DescriptorEncoder;
 
+use crate::collector::agent::AGENT_METRICS;
+use crate::common::types::ListFilter;
+use crate::managers::cache::CacheManager;
+use crate::managers::map::MapManager;
+use crate::managers::metrics::{MetricFamily, MetricKind, MetricsManager, Sample};
 use crate::managers::registry::RegistryManager;
-use agent_api::ProgramState;
 
-#[derive(Debug)]
+pub(crate) mod agent;
+
+#[derive(Debug, Clone)]
 pub(crate) struct Collector {
     registry_manager: RegistryManager,
+    map_manager: MapManager,
+    cache_manager: CacheManager,
+    metrics_manager: MetricsManager,
 }
 
 impl Collector {
-    pub(crate) fn new(registry_manager: RegistryManager) -> Self {
-        Self { registry_manager }
+    pub(crate) fn new(
+        registry_manager: RegistryManager,
+        map_manager: MapManager,
+        cache_manager: CacheManager,
+        metrics_manager: MetricsManager,
+    ) -> Self {
+        Self {
+            registry_manager,
+            map_manager,
+            cache_manager,
+            metrics_manager,
+        }
     }
-}
 
-impl PrometheusCollector for Collector {
-    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
-        let progs = self.registry_manager.builtin.list();
+    fn agent_stats(&self) -> Vec<MetricFamily> {
+        let mut programs = MetricFamily::new(
+            "agent_programs",
+            "number of registered programs by type and state",
+            None,
+            MetricKind::Gauge,
+        );
+        let mut counts: BTreeMap<(String, String), usize> = BTreeMap::new();
+        for prog in self
+            .registry_manager
+            .list_programs(ListFilter::new(None, HashMap::new()))
+        {
+            let key = (
+                format!("{:?}", prog.get_type()),
+                format!("{:?}", prog.get_state()),
+            );
+            *counts.entry(key).or_default() += 1;
+        }
+        for ((program_type, state), count) in counts {
+            programs.samples.push(Sample::new(
+                [("program_type", program_type), ("state", state)],
+                count as f64,
+            ));
+        }
+
+        let mut cache_objects = MetricFamily::new(
+            "agent_kube_cache_objects",
+            "number of objects in the Kubernetes cache by kind",
+            None,
+            MetricKind::Gauge,
+        );
+        let cache = &self.cache_manager;
+        let counts = [
+            ("Pod", cache.pods.state().len()),
+            ("Node", cache.nodes.state().len()),
+            ("Service", cache.services.state().len()),
+            ("ReplicaSet", cache.replicasets.state().len()),
+            ("Deployment", cache.deployments.state().len()),
+            ("StatefulSet", cache.statefulsets.state().len()),
+            ("DaemonSet", cache.daemonsets.state().len()),
+            ("Job", cache.jobs.state().len()),
+            ("CronJob", cache.cronjobs.state().len()),
+            ("EndpointSlice", cache.endpointslices.state().len()),
+        ];
+        for (kind, count) in counts {
+            cache_objects
+                .samples
+                .push(Sample::new([("kind", kind.to_string())], count as f64));
+        }
+
+        let mut families = vec![programs, cache_objects];
+        families.extend(AGENT_METRICS.families());
+        families
+    }
+
+    fn map_stats(&self) -> Vec<MetricFamily> {
+        let mut entries = MetricFamily::new(
+            "ebpf_map_entries",
+            "current number of entries in a bound eBPF map",
+            None,
+            MetricKind::Gauge,
+        );
+        let mut max_entries = MetricFamily::new(
+            "ebpf_map_max_entries",
+            "maximum number of entries of a bound eBPF map",
+            None,
+            MetricKind::Gauge,
+        );
+        let mut eviction_rate = MetricFamily::new(
+            "ebpf_map_estimated_eviction_rate",
+            "estimated entries evicted per second from a full bound eBPF map",
+            None,
+            MetricKind::Gauge,
+        );
+        for (program, map, stats) in self.map_manager.stats() {
+            let labels = [("program", program), ("map", map)];
+            entries
+                .samples
+                .push(Sample::new(labels.clone(), stats.entries as f64));
+            max_entries
+                .samples
+                .push(Sample::new(labels.clone(), stats.max_entries as f64));
+            eviction_rate
+                .samples
+                .push(Sample::new(labels, stats.eviction_rate));
+        }
 
-        // 筛选出状态为 Running 的 progs
-        let running_progs: Vec<_> = progs
+        vec![entries, max_entries, eviction_rate]
+    }
+
+    /// Returns the metric families of all programs, from their latest snapshots, of the
+    /// eBPF maps bound to them and of the agent itself. `/metrics` renders exactly these,
+    /// and the exporters push them.
+    pub(crate) fn families(&self) -> Vec<MetricFamily> {
+        let mut families: Vec<MetricFamily> = self
+            .metrics_manager
+            .snapshots()
             .iter()
-            .filter(|prog| prog.get_state() == ProgramState::Running)
+            .flat_map(|(_, families)| families.iter().cloned())
             .collect();
+        families.extend(self.map_stats());
+        families.extend(self.agent_stats());
+        families
+    }
+}
 
-        for prog in running_progs {
-            if let Err(e) = prog.collect(&mut encoder) {
-                eprintln!("Failed to collect metrics: {:?}", e);
-            }
+impl PrometheusCollector for Collector {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        // Programs are only read from their latest snapshot, so that scrapes never
+        // change program state.
+        for family in self.families() {
+            family.encode(&mut encoder)?;
         }
+        Ok(())
+    }
+}
+
+/// Renders a fixed set of metric families, such as those of a single program.
+#[derive(Debug)]
+pub(crate) struct FamiliesCollector(pub Vec<MetricFamily>);
 
+impl PrometheusCollector for FamiliesCollector {
+    fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        for family in self.0.iter() {
+            family.encode(&mut encoder)?;
+        }
         Ok(())
     }
 }
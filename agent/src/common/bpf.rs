@@ -0,0 +1,239 @@
+use std::ffi::CString;
+use std::io;
+use std::mem::size_of;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use nix::libc::{c_long, syscall, SYS_bpf, ENOENT};
+
+const BPF_MAP_LOOKUP_ELEM: c_long = 1;
+const BPF_MAP_GET_NEXT_KEY: c_long = 4;
+const BPF_OBJ_GET: c_long = 7;
+const BPF_OBJ_GET_INFO_BY_FD: c_long = 15;
+const BPF_BTF_GET_FD_BY_ID: c_long = 19;
+
+const BPF_MAP_TYPE_PERCPU_HASH: u32 = 5;
+const BPF_MAP_TYPE_PERCPU_ARRAY: u32 = 6;
+const BPF_MAP_TYPE_LRU_PERCPU_HASH: u32 = 10;
+
+#[repr(C)]
+#[derive(Default)]
+struct MapElemAttr {
+    map_fd: u32,
+    _pad: u32,
+    key: u64,
+    value_or_next_key: u64,
+    flags: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct ObjGetAttr {
+    pathname: u64,
+    bpf_fd: u32,
+    file_flags: u32,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct InfoByFdAttr {
+    bpf_fd: u32,
+    info_len: u32,
+    info: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct GetFdByIdAttr {
+    id: u32,
+    next_id: u32,
+    open_flags: u32,
+}
+
+/// Mirror of the kernel's `struct bpf_map_info`.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct MapInfo {
+    pub map_type: u32,
+    pub id: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+    pub map_flags: u32,
+    pub name: [u8; 16],
+    pub ifindex: u32,
+    pub btf_vmlinux_value_type_id: u32,
+    pub netns_dev: u64,
+    pub netns_ino: u64,
+    pub btf_id: u32,
+    pub btf_key_type_id: u32,
+    pub btf_value_type_id: u32,
+    _pad: u32,
+    pub map_extra: u64,
+}
+
+/// Mirror of the kernel's `struct bpf_btf_info`.
+#[repr(C)]
+#[derive(Default)]
+struct BtfInfo {
+    btf: u64,
+    btf_size: u32,
+    id: u32,
+    name: u64,
+    name_len: u32,
+    kernel_btf: u32,
+}
+
+fn sys_bpf<T>(cmd: c_long, attr: &mut T) -> io::Result<c_long> {
+    let ret = unsafe { syscall(SYS_bpf, cmd, attr as *mut T, size_of::<T>()) };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ret)
+}
+
+fn sys_bpf_fd<T>(cmd: c_long, attr: &mut T) -> io::Result<OwnedFd> {
+    let fd = sys_bpf(cmd, attr)?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as i32) })
+}
+
+fn obj_info<T: Default>(fd: &OwnedFd, info: &mut T) -> io::Result<()> {
+    let mut attr = InfoByFdAttr {
+        bpf_fd: fd.as_raw_fd() as u32,
+        info_len: size_of::<T>() as u32,
+        info: info as *mut T as u64,
+    };
+    sys_bpf(BPF_OBJ_GET_INFO_BY_FD, &mut attr)?;
+    Ok(())
+}
+
+/// Opens a pinned bpf object, returning its file descriptor.
+pub(crate) fn obj_get(path: &Path) -> io::Result<OwnedFd> {
+    let pathname = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut attr = ObjGetAttr {
+        pathname: pathname.as_ptr() as u64,
+        ..Default::default()
+    };
+    sys_bpf_fd(BPF_OBJ_GET, &mut attr)
+}
+
+/// Returns the kernel's view of the map behind `fd`.
+pub(crate) fn map_info(fd: &OwnedFd) -> io::Result<MapInfo> {
+    let mut info = MapInfo::default();
+    obj_info(fd, &mut info)?;
+    Ok(info)
+}
+
+/// Fetches the raw BTF blob registered in the kernel under `btf_id`.
+pub(crate) fn btf_data(btf_id: u32) -> io::Result<Vec<u8>> {
+    let mut attr = GetFdByIdAttr {
+        id: btf_id,
+        ..Default::default()
+    };
+    let fd = sys_bpf_fd(BPF_BTF_GET_FD_BY_ID, &mut attr)?;
+
+    // The first call only reports the size of the blob.
+    let mut info = BtfInfo::default();
+    obj_info(&fd, &mut info)?;
+
+    let mut data = vec![0u8; info.btf_size as usize];
+    let mut info = BtfInfo {
+        btf: data.as_mut_ptr() as u64,
+        btf_size: data.len() as u32,
+        ..Default::default()
+    };
+    obj_info(&fd, &mut info)?;
+    data.truncate(info.btf_size as usize);
+    Ok(data)
+}
+
+fn map_get_next_key(fd: &OwnedFd, key: Option<&[u8]>, next_key: &mut [u8]) -> io::Result<bool> {
+    let mut attr = MapElemAttr {
+        map_fd: fd.as_raw_fd() as u32,
+        key: key.map(|k| k.as_ptr() as u64).unwrap_or_default(),
+        value_or_next_key: next_key.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    match sys_bpf(BPF_MAP_GET_NEXT_KEY, &mut attr) {
+        Ok(_) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(ENOENT) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn map_lookup_elem(fd: &OwnedFd, key: &[u8], value: &mut [u8]) -> io::Result<bool> {
+    let mut attr = MapElemAttr {
+        map_fd: fd.as_raw_fd() as u32,
+        key: key.as_ptr() as u64,
+        value_or_next_key: value.as_mut_ptr() as u64,
+        ..Default::default()
+    };
+    match sys_bpf(BPF_MAP_LOOKUP_ELEM, &mut attr) {
+        Ok(_) => Ok(true),
+        Err(e) if e.raw_os_error() == Some(ENOENT) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+fn for_each_key(
+    fd: &OwnedFd,
+    info: &MapInfo,
+    limit: usize,
+    mut f: impl FnMut(&[u8]) -> io::Result<()>,
+) -> io::Result<()> {
+    // Entries may be deleted while iterating, which restarts the walk, so never visit
+    // more keys than the limit.
+    let mut key: Option<Vec<u8>> = None;
+    for _ in 0..limit {
+        let mut next_key = vec![0u8; info.key_size as usize];
+        if !map_get_next_key(fd, key.as_deref(), &mut next_key)? {
+            break;
+        }
+        f(&next_key)?;
+        key = Some(next_key);
+    }
+    Ok(())
+}
+
+/// Reads up to `limit` raw key/value pairs from the map pinned at `path`.
+pub(crate) fn dump_pinned_map(
+    path: &Path,
+    limit: Option<usize>,
+) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let fd = obj_get(path)?;
+    let info = map_info(&fd)?;
+    if matches!(
+        info.map_type,
+        BPF_MAP_TYPE_PERCPU_HASH | BPF_MAP_TYPE_PERCPU_ARRAY | BPF_MAP_TYPE_LRU_PERCPU_HASH
+    ) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "per-CPU maps are not supported",
+        ));
+    }
+
+    let mut entries = Vec::new();
+    let limit = limit.unwrap_or(info.max_entries as usize);
+    for_each_key(&fd, &info, limit, |key| {
+        let mut value = vec![0u8; info.value_size as usize];
+        if map_lookup_elem(&fd, key, &mut value)? {
+            entries.push((key.to_vec(), value));
+        }
+        Ok(())
+    })?;
+    Ok(entries)
+}
+
+/// Returns the info of the map pinned at `path` together with all of its keys.
+pub(crate) fn pinned_map_keys(path: &Path) -> io::Result<(MapInfo, Vec<Vec<u8>>)> {
+    let fd = obj_get(path)?;
+    let info = map_info(&fd)?;
+    let mut keys = Vec::new();
+    for_each_key(&fd, &info, info.max_entries as usize, |key| {
+        keys.push(key.to_vec());
+        Ok(())
+    })?;
+    Ok((info, keys))
+}
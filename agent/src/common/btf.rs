@@ -0,0 +1,423 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Error};
+
+use crate::common::bpf::{btf_data, map_info, obj_get};
+
+const BTF_MAGIC: u16 = 0xeb9f;
+
+const BTF_KIND_INT: u32 = 1;
+const BTF_KIND_PTR: u32 = 2;
+const BTF_KIND_ARRAY: u32 = 3;
+const BTF_KIND_STRUCT: u32 = 4;
+const BTF_KIND_UNION: u32 = 5;
+const BTF_KIND_ENUM: u32 = 6;
+const BTF_KIND_TYPEDEF: u32 = 8;
+const BTF_KIND_VOLATILE: u32 = 9;
+const BTF_KIND_CONST: u32 = 10;
+const BTF_KIND_RESTRICT: u32 = 11;
+const BTF_KIND_FUNC_PROTO: u32 = 13;
+const BTF_KIND_VAR: u32 = 14;
+const BTF_KIND_DATASEC: u32 = 15;
+const BTF_KIND_FLOAT: u32 = 16;
+const BTF_KIND_DECL_TAG: u32 = 17;
+const BTF_KIND_TYPE_TAG: u32 = 18;
+const BTF_KIND_ENUM64: u32 = 19;
+
+/// A field of a type as laid out in memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FieldLayout {
+    pub name: String,
+    pub offset: usize,
+    pub size: usize,
+}
+
+/// The memory layout of a map key or value type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct TypeLayout {
+    pub name: String,
+    pub size: usize,
+    pub fields: Vec<FieldLayout>,
+}
+
+/// The key and value layout a program expects a map to have.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MapLayout {
+    pub key: TypeLayout,
+    pub value: TypeLayout,
+}
+
+pub(crate) fn field_size<T, F>(_: fn(&T) -> &F) -> usize {
+    std::mem::size_of::<F>()
+}
+
+/// Builds the [`TypeLayout`] of a `#[repr(C)]` struct from its field list.
+macro_rules! type_layout {
+    ($ty:ident { $($field:ident),* $(,)? }) => {
+        $crate::common::btf::TypeLayout {
+            name: stringify!($ty).to_string(),
+            size: std::mem::size_of::<$ty>(),
+            fields: vec![$(
+                $crate::common::btf::FieldLayout {
+                    name: stringify!($field).to_string(),
+                    offset: std::mem::offset_of!($ty, $field),
+                    size: $crate::common::btf::field_size(|t: &$ty| &t.$field),
+                },
+            )*],
+        }
+    };
+}
+
+pub(crate) use type_layout;
+
+#[derive(Debug, Clone)]
+struct BtfMember {
+    name_off: u32,
+    type_id: u32,
+    bit_offset: u32,
+}
+
+#[derive(Debug, Clone)]
+struct BtfType {
+    name_off: u32,
+    kind: u32,
+    size_or_type: u32,
+    members: Vec<BtfMember>,
+    array: Option<(u32, u32)>,
+}
+
+/// A minimal BTF reader, just enough to resolve the layout of map keys and values.
+#[derive(Debug)]
+pub(crate) struct Btf {
+    types: Vec<BtfType>,
+    strings: Vec<u8>,
+}
+
+fn read_u16(data: &[u8], off: usize) -> Result<u16, Error> {
+    data.get(off..off + 2)
+        .map(|b| u16::from_ne_bytes([b[0], b[1]]))
+        .ok_or(anyhow!("BTF data truncated at offset {}", off))
+}
+
+fn read_u32(data: &[u8], off: usize) -> Result<u32, Error> {
+    data.get(off..off + 4)
+        .map(|b| u32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+        .ok_or(anyhow!("BTF data truncated at offset {}", off))
+}
+
+impl Btf {
+    pub(crate) fn parse(data: &[u8]) -> Result<Self, Error> {
+        let magic = read_u16(data, 0)?;
+        if magic != BTF_MAGIC {
+            bail!("invalid BTF magic {:#x}", magic);
+        }
+        let hdr_len = read_u32(data, 4)? as usize;
+        let type_off = read_u32(data, 8)? as usize;
+        let type_len = read_u32(data, 12)? as usize;
+        let str_off = read_u32(data, 16)? as usize;
+        let str_len = read_u32(data, 20)? as usize;
+
+        let strings = data
+            .get(hdr_len + str_off..hdr_len + str_off + str_len)
+            .ok_or(anyhow!("BTF string section out of bounds"))?
+            .to_vec();
+        let type_data = data
+            .get(hdr_len + type_off..hdr_len + type_off + type_len)
+            .ok_or(anyhow!("BTF type section out of bounds"))?;
+
+        // Type ID 0 is reserved for void.
+        let mut types = vec![BtfType {
+            name_off: 0,
+            kind: 0,
+            size_or_type: 0,
+            members: vec![],
+            array: None,
+        }];
+        let mut off = 0;
+        while off < type_data.len() {
+            let name_off = read_u32(type_data, off)?;
+            let info = read_u32(type_data, off + 4)?;
+            let size_or_type = read_u32(type_data, off + 8)?;
+            off += 12;
+
+            let vlen = (info & 0xffff) as usize;
+            let kind = (info >> 24) & 0x1f;
+            let kind_flag = info >> 31 == 1;
+            let mut members = vec![];
+            let mut array = None;
+            match kind {
+                BTF_KIND_INT | BTF_KIND_VAR | BTF_KIND_DECL_TAG => off += 4,
+                BTF_KIND_ARRAY => {
+                    array = Some((read_u32(type_data, off)?, read_u32(type_data, off + 8)?));
+                    off += 12;
+                }
+                BTF_KIND_STRUCT | BTF_KIND_UNION => {
+                    for _ in 0..vlen {
+                        let offset = read_u32(type_data, off + 8)?;
+                        members.push(BtfMember {
+                            name_off: read_u32(type_data, off)?,
+                            type_id: read_u32(type_data, off + 4)?,
+                            bit_offset: if kind_flag { offset & 0xffffff } else { offset },
+                        });
+                        off += 12;
+                    }
+                }
+                BTF_KIND_ENUM | BTF_KIND_FUNC_PROTO => off += 8 * vlen,
+                BTF_KIND_DATASEC | BTF_KIND_ENUM64 => off += 12 * vlen,
+                _ => {}
+            }
+            types.push(BtfType {
+                name_off,
+                kind,
+                size_or_type,
+                members,
+                array,
+            });
+        }
+
+        Ok(Self { types, strings })
+    }
+
+    fn type_by_id(&self, id: u32) -> Result<&BtfType, Error> {
+        self.types
+            .get(id as usize)
+            .ok_or(anyhow!("BTF type {} not found", id))
+    }
+
+    fn string_at(&self, off: u32) -> String {
+        let bytes = self.strings.get(off as usize..).unwrap_or_default();
+        let end = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).to_string()
+    }
+
+    /// Follows typedefs and qualifiers to the underlying type.
+    fn resolve(&self, mut id: u32) -> Result<u32, Error> {
+        for _ in 0..32 {
+            match self.type_by_id(id)?.kind {
+                BTF_KIND_TYPEDEF | BTF_KIND_VOLATILE | BTF_KIND_CONST | BTF_KIND_RESTRICT
+                | BTF_KIND_TYPE_TAG => id = self.type_by_id(id)?.size_or_type,
+                _ => return Ok(id),
+            }
+        }
+        bail!("BTF type {} has too many levels of indirection", id)
+    }
+
+    fn type_size(&self, id: u32) -> Result<usize, Error> {
+        let ty = self.type_by_id(self.resolve(id)?)?;
+        Ok(match ty.kind {
+            BTF_KIND_INT | BTF_KIND_STRUCT | BTF_KIND_UNION | BTF_KIND_ENUM | BTF_KIND_ENUM64
+            | BTF_KIND_FLOAT => ty.size_or_type as usize,
+            BTF_KIND_PTR => std::mem::size_of::<usize>(),
+            BTF_KIND_ARRAY => {
+                let (elem, nelems) = ty.array.ok_or(anyhow!("BTF array without element"))?;
+                self.type_size(elem)? * nelems as usize
+            }
+            kind => bail!("BTF kind {} has no size", kind),
+        })
+    }
+
+    /// Returns the layout of the type with the given ID as recorded in BTF.
+    pub(crate) fn type_layout(&self, id: u32) -> Result<TypeLayout, Error> {
+        let resolved = self.resolve(id)?;
+        let ty = self.type_by_id(resolved)?;
+        let mut fields = vec![];
+        if ty.kind == BTF_KIND_STRUCT || ty.kind == BTF_KIND_UNION {
+            for member in ty.members.iter() {
+                fields.push(FieldLayout {
+                    name: self.string_at(member.name_off),
+                    offset: (member.bit_offset / 8) as usize,
+                    size: self.type_size(member.type_id)?,
+                });
+            }
+        }
+        Ok(TypeLayout {
+            name: self.string_at(ty.name_off),
+            size: self.type_size(resolved)?,
+            fields,
+        })
+    }
+}
+
+fn compare_layouts(what: &str, expected: &TypeLayout, actual: &TypeLayout) -> Result<(), Error> {
+    if expected.size != actual.size {
+        bail!(
+            "{} size mismatch: {} is {} bytes, map has {} bytes",
+            what,
+            expected.name,
+            expected.size,
+            actual.size
+        );
+    }
+    // BTF without member information (e.g. a plain integer) can only be checked by size.
+    if actual.fields.is_empty() {
+        return Ok(());
+    }
+    for field in expected.fields.iter() {
+        match actual.fields.iter().find(|f| f.name == field.name) {
+            Some(f) if f.offset == field.offset && f.size == field.size => {}
+            Some(f) => bail!(
+                "{} field {}.{} mismatch: expected offset {} size {}, map has offset {} size {}",
+                what,
+                expected.name,
+                field.name,
+                field.offset,
+                field.size,
+                f.offset,
+                f.size
+            ),
+            None => bail!(
+                "{} field {}.{} not found in map type {}",
+                what,
+                expected.name,
+                field.name,
+                actual.name
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Checks that the map pinned at `pin_path` has the key and value layout a program expects.
+///
+/// Sizes are always compared. If the map carries BTF type information, the field names,
+/// offsets and sizes of the declared types are compared as well.
+pub(crate) fn validate_pinned_map(pin_path: &Path, layout: &MapLayout) -> Result<(), Error> {
+    let fd = obj_get(pin_path)
+        .with_context(|| format!("failed to open pinned map {}", pin_path.display()))?;
+    let info = map_info(&fd)
+        .with_context(|| format!("failed to get info of map {}", pin_path.display()))?;
+
+    if info.key_size as usize != layout.key.size {
+        bail!(
+            "key size mismatch for map {}: {} is {} bytes, map has {} bytes",
+            pin_path.display(),
+            layout.key.name,
+            layout.key.size,
+            info.key_size
+        );
+    }
+    if info.value_size as usize != layout.value.size {
+        bail!(
+            "value size mismatch for map {}: {} is {} bytes, map has {} bytes",
+            pin_path.display(),
+            layout.value.name,
+            layout.value.size,
+            info.value_size
+        );
+    }
+
+    if info.btf_id == 0 || info.btf_key_type_id == 0 || info.btf_value_type_id == 0 {
+        log::debug!(
+            "Map {} has no BTF key/value types, only sizes were checked",
+            pin_path.display()
+        );
+        return Ok(());
+    }
+
+    let data = btf_data(info.btf_id)
+        .with_context(|| format!("failed to read BTF {} from kernel", info.btf_id))?;
+    let btf = Btf::parse(&data)?;
+    compare_layouts("key", &layout.key, &btf.type_layout(info.btf_key_type_id)?)?;
+    compare_layouts(
+        "value",
+        &layout.value,
+        &btf.type_layout(info.btf_value_type_id)?,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(C)]
+    struct Key {
+        addr: u32,
+        port: u16,
+        pad: u16,
+        bytes: u64,
+    }
+
+    fn push_type(buf: &mut Vec<u8>, name_off: u32, kind: u32, vlen: u32, size_or_type: u32) {
+        buf.extend_from_slice(&name_off.to_ne_bytes());
+        buf.extend_from_slice(&((kind << 24) | vlen).to_ne_bytes());
+        buf.extend_from_slice(&size_or_type.to_ne_bytes());
+    }
+
+    fn push_u32s(buf: &mut Vec<u8>, values: &[u32]) {
+        for v in values {
+            buf.extend_from_slice(&v.to_ne_bytes());
+        }
+    }
+
+    /// Encodes `struct Key` with a typedef in front of it, as a compiler would.
+    fn key_btf() -> Vec<u8> {
+        let strings = b"\0Key\0addr\0port\0pad\0bytes\0u32\0u16\0u64\0key_t\0".to_vec();
+        let mut types = vec![];
+        // [1] u32, [2] u16, [3] u64
+        push_type(&mut types, 25, BTF_KIND_INT, 0, 4);
+        push_u32s(&mut types, &[32]);
+        push_type(&mut types, 29, BTF_KIND_INT, 0, 2);
+        push_u32s(&mut types, &[16]);
+        push_type(&mut types, 33, BTF_KIND_INT, 0, 8);
+        push_u32s(&mut types, &[64]);
+        // [4] struct Key
+        push_type(&mut types, 1, BTF_KIND_STRUCT, 4, 16);
+        push_u32s(&mut types, &[5, 1, 0, 10, 2, 32, 15, 2, 48, 19, 3, 64]);
+        // [5] typedef key_t
+        push_type(&mut types, 37, BTF_KIND_TYPEDEF, 0, 4);
+
+        let mut data = vec![];
+        data.extend_from_slice(&BTF_MAGIC.to_ne_bytes());
+        data.extend_from_slice(&[1, 0]);
+        push_u32s(
+            &mut data,
+            &[
+                24,
+                0,
+                types.len() as u32,
+                types.len() as u32,
+                strings.len() as u32,
+            ],
+        );
+        data.extend_from_slice(&types);
+        data.extend_from_slice(&strings);
+        data
+    }
+
+    #[test]
+    fn test_btf_type_layout_matches_declared_layout() {
+        let btf = Btf::parse(&key_btf()).unwrap();
+        let actual = btf.type_layout(5).unwrap();
+        let expected = type_layout!(Key {
+            addr,
+            port,
+            pad,
+            bytes
+        });
+
+        assert_eq!(actual.name, "Key");
+        assert_eq!(actual, expected);
+        assert!(compare_layouts("key", &expected, &actual).is_ok());
+    }
+
+    #[test]
+    fn test_btf_type_layout_mismatch() {
+        let btf = Btf::parse(&key_btf()).unwrap();
+        let actual = btf.type_layout(4).unwrap();
+        let mut expected = type_layout!(Key {
+            addr,
+            port,
+            pad,
+            bytes
+        });
+        expected.fields[1].offset = 6;
+
+        let err = compare_layouts("key", &expected, &actual).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "key field Key.port mismatch: expected offset 6 size 2, map has offset 4 size 2"
+        );
+    }
+}
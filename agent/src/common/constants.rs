@@ -6,3 +6,9 @@ pub mod directories {
 }
 
 pub const DEFAULT_INTERVAL: u64 = 15;
+pub const DEFAULT_EDGE_TTL: u64 = 600;
+pub const MAP_PRESSURE_THRESHOLD: f64 = 0.9;
+pub const NODE_NAME_ENV: &str = "KUBE_NODE_NAME";
+pub const HEALTH_CHECK_INTERVAL: u64 = 5;
+pub const HEALTH_CHECK_TIMEOUT: u64 = 2;
+pub const EXPORT_TIMEOUT: u64 = 10;
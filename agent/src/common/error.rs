@@ -0,0 +1,216 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use thiserror::Error;
+use tonic::{Code, Status};
+use tonic_types::{ErrorDetails, StatusExt};
+
+use agent_api::ParseError;
+
+/// Domain reported in the `google.rpc.ErrorInfo` of agent errors.
+const ERROR_DOMAIN: &str = "agent.eva";
+
+/// Errors returned by the agent API, each mapped to a gRPC status code.
+#[derive(Error, Debug)]
+pub(crate) enum AgentError {
+    #[error("Program {name} not found")]
+    ProgramNotFound { name: String },
+    #[error("Map {map} is not bound to program {program}")]
+    MapNotBound { program: String, map: String },
+    #[error("Invalid {field}: {reason}")]
+    InvalidArgument { field: String, reason: String },
+    #[error("Program {name} is already running")]
+    AlreadyRunning { name: String },
+    #[error("Program {name} is in an invalid state: {state}")]
+    InvalidState { name: String, state: String },
+    #[error("Required eBPF program {program} of map {map} is not loaded")]
+    EbpfProgramNotLoaded { program: String, map: String },
+    #[error("Failed to initialize program {name}: {source:#}")]
+    InitFailed {
+        name: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("bpfman is unavailable: {0}")]
+    BpfmanUnavailable(String),
+    #[error("bpfman request failed: {message}")]
+    BpfmanFailed { code: Code, message: String },
+    #[error("Kubernetes cache has not synced yet")]
+    CacheNotSynced,
+    #[error("Failed to read map {path}: {source}")]
+    MapRead {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{0:#}")]
+    Internal(#[from] anyhow::Error),
+}
+
+impl From<ParseError> for AgentError {
+    fn from(e: ParseError) -> Self {
+        let field = match &e {
+            ParseError::InvalidProgramType { .. } => "program_type",
+            ParseError::InvalidProgramState { .. } => "state",
+            ParseError::InvalidBytecodeImagePullPolicy { .. } => "image_pull_policy",
+            _ => "bytecode",
+        };
+        AgentError::InvalidArgument {
+            field: field.to_string(),
+            reason: e.to_string(),
+        }
+    }
+}
+
+impl AgentError {
+    fn code(&self) -> Code {
+        match self {
+            AgentError::ProgramNotFound { .. } | AgentError::MapNotBound { .. } => Code::NotFound,
+            AgentError::InvalidArgument { .. } => Code::InvalidArgument,
+            AgentError::AlreadyRunning { .. } => Code::AlreadyExists,
+            AgentError::InvalidState { .. }
+            | AgentError::EbpfProgramNotLoaded { .. }
+            | AgentError::InitFailed { .. } => Code::FailedPrecondition,
+            AgentError::BpfmanUnavailable(_) | AgentError::CacheNotSynced => Code::Unavailable,
+            AgentError::BpfmanFailed { code, .. } => *code,
+            AgentError::MapRead { source, .. } => match source.kind() {
+                std::io::ErrorKind::NotFound => Code::NotFound,
+                std::io::ErrorKind::Unsupported => Code::FailedPrecondition,
+                _ => Code::Internal,
+            },
+            AgentError::Internal(_) => Code::Internal,
+        }
+    }
+
+    /// Machine readable reason reported in the `google.rpc.ErrorInfo`.
+    fn reason(&self) -> &'static str {
+        match self {
+            AgentError::ProgramNotFound { .. } => "PROGRAM_NOT_FOUND",
+            AgentError::MapNotBound { .. } => "MAP_NOT_BOUND",
+            AgentError::InvalidArgument { .. } => "INVALID_ARGUMENT",
+            AgentError::AlreadyRunning { .. } => "PROGRAM_ALREADY_RUNNING",
+            AgentError::InvalidState { .. } => "INVALID_PROGRAM_STATE",
+            AgentError::EbpfProgramNotLoaded { .. } => "EBPF_PROGRAM_NOT_LOADED",
+            AgentError::InitFailed { .. } => "PROGRAM_INIT_FAILED",
+            AgentError::BpfmanUnavailable(_) => "BPFMAN_UNAVAILABLE",
+            AgentError::BpfmanFailed { .. } => "BPFMAN_REQUEST_FAILED",
+            AgentError::CacheNotSynced => "CACHE_NOT_SYNCED",
+            AgentError::MapRead { .. } => "MAP_READ_FAILED",
+            AgentError::Internal(_) => "INTERNAL",
+        }
+    }
+
+    fn details(&self) -> ErrorDetails {
+        let mut details =
+            ErrorDetails::with_error_info(self.reason(), ERROR_DOMAIN, HashMap::new());
+        match self {
+            AgentError::ProgramNotFound { name } => {
+                details.set_resource_info("program", name, "", self.to_string());
+            }
+            AgentError::MapNotBound { program, map } => {
+                details.set_resource_info("map", map, program, self.to_string());
+            }
+            AgentError::InvalidArgument { field, reason } => {
+                details.add_bad_request_violation(field, reason);
+            }
+            AgentError::AlreadyRunning { name } => {
+                details.set_resource_info("program", name, "", self.to_string());
+            }
+            AgentError::InvalidState { name, state } => {
+                details.add_precondition_failure_violation("STATE", name, state);
+            }
+            AgentError::EbpfProgramNotLoaded { program, .. } => {
+                details.add_precondition_failure_violation(
+                    "EBPF_PROGRAM",
+                    program,
+                    "the eBPF program must be loaded through bpfman first",
+                );
+            }
+            AgentError::InitFailed { name, source } => {
+                details.add_precondition_failure_violation("INIT", name, format!("{:#}", source));
+            }
+            AgentError::BpfmanUnavailable(_) | AgentError::CacheNotSynced => {
+                details.set_retry_info(Some(Duration::from_secs(1)));
+            }
+            AgentError::BpfmanFailed { .. }
+            | AgentError::MapRead { .. }
+            | AgentError::Internal(_) => {}
+        }
+        details
+    }
+}
+
+impl From<Status> for AgentError {
+    /// Only bpfman being unreachable or slow is worth a retry; other errors of bpfman
+    /// keep their code.
+    fn from(status: Status) -> Self {
+        match status.code() {
+            Code::Unavailable | Code::DeadlineExceeded => {
+                AgentError::BpfmanUnavailable(status.message().to_string())
+            }
+            code => AgentError::BpfmanFailed {
+                code,
+                message: status.message().to_string(),
+            },
+        }
+    }
+}
+
+impl From<AgentError> for Status {
+    fn from(e: AgentError) -> Self {
+        Status::with_error_details(e.code(), e.to_string(), e.details())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_agent_error_codes() {
+        let cases = [
+            (
+                AgentError::ProgramNotFound {
+                    name: "conn-tracer".to_string(),
+                },
+                Code::NotFound,
+            ),
+            (
+                AgentError::from(ParseError::InvalidProgramType { program_type: 7 }),
+                Code::InvalidArgument,
+            ),
+            (
+                AgentError::AlreadyRunning {
+                    name: "conn-tracer".to_string(),
+                },
+                Code::AlreadyExists,
+            ),
+            (
+                AgentError::BpfmanUnavailable("connection refused".to_string()),
+                Code::Unavailable,
+            ),
+            (AgentError::CacheNotSynced, Code::Unavailable),
+            (
+                AgentError::from(Status::deadline_exceeded("timed out")),
+                Code::Unavailable,
+            ),
+            (
+                AgentError::from(Status::permission_denied("not allowed")),
+                Code::PermissionDenied,
+            ),
+            (
+                AgentError::MapRead {
+                    path: "/run/bpfman/fs/maps/1/CONNECTIONS".to_string(),
+                    source: std::io::ErrorKind::NotFound.into(),
+                },
+                Code::NotFound,
+            ),
+        ];
+        for (error, code) in cases {
+            let message = error.to_string();
+            let status = Status::from(error);
+            assert_eq!(status.code(), code);
+            assert_eq!(status.message(), message);
+        }
+    }
+}
@@ -1,3 +1,6 @@
+pub(crate) mod bpf;
+pub(crate) mod btf;
 pub(crate) mod constants;
+pub(crate) mod error;
 pub(crate) mod types;
 pub(crate) mod utils;
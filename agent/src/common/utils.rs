@@ -46,3 +46,11 @@ pub fn fnv_hash(s: &str) -> u32 {
     hasher.write(s.as_bytes());
     hasher.finish() as u32
 }
+
+/// Reads a plain-old-data value from the start of `bytes`, if it is large enough.
+pub fn read_pod<T: Copy>(bytes: &[u8]) -> Option<T> {
+    if bytes.len() < std::mem::size_of::<T>() {
+        return None;
+    }
+    Some(unsafe { std::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
@@ -0,0 +1,51 @@
+//! Exporters pushing the metric families of [`crate::collector::Collector`] to remote
+//! backends, for environments where `/metrics` cannot be scraped.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::sync::Arc;
+
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::client::legacy::connect::HttpConnector;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+
+pub(crate) mod otlp;
+pub(crate) mod remote_write;
+
+/// Builds the connector of the HTTP exporters. https:// receivers are verified against
+/// `ca_cert` if set, or the native roots otherwise.
+pub(crate) fn https_connector(
+    ca_cert: Option<&Path>,
+) -> anyhow::Result<HttpsConnector<HttpConnector>> {
+    let provider = Arc::new(default_provider());
+    let builder = match ca_cert {
+        Some(ca_cert) => {
+            let mut roots = RootCertStore::empty();
+            for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(ca_cert)?)) {
+                roots.add(cert?)?;
+            }
+            let tls_config = ClientConfig::builder_with_provider(provider)
+                .with_safe_default_protocol_versions()?
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            HttpsConnectorBuilder::new().with_tls_config(tls_config)
+        }
+        None => HttpsConnectorBuilder::new().with_provider_and_native_roots(provider)?,
+    };
+    Ok(builder.https_or_http().enable_http1().build())
+}
+
+/// Parses `key=value` pairs as given on the command line.
+pub(crate) fn parse_key_values(pairs: &[String]) -> anyhow::Result<Vec<(String, String)>> {
+    pairs
+        .iter()
+        .map(|pair| {
+            pair.split_once('=')
+                .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+                .filter(|(key, _)| !key.is_empty())
+                .ok_or_else(|| anyhow::anyhow!("Expected key=value, got {:?}", pair))
+        })
+        .collect()
+}
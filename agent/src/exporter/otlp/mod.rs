@@ -0,0 +1,443 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::Request as HttpRequest;
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client as HttpClient;
+use hyper_util::rt::TokioExecutor;
+use log::{debug, info, warn};
+use prost::Message;
+use tokio::sync::broadcast::Receiver;
+use tokio::time;
+use tonic::codec::ProstCodec;
+use tonic::codegen::http::uri::PathAndQuery;
+use tonic::metadata::{AsciiMetadataValue, MetadataKey};
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Endpoint};
+
+use crate::collector::Collector;
+use crate::common::constants::{EXPORT_TIMEOUT, NODE_NAME_ENV};
+use crate::exporter::{https_connector, parse_key_values};
+use crate::managers::metrics::{MetricFamily, MetricKind};
+use crate::progs::types::ShutdownSignal;
+use crate::Args;
+
+pub(crate) mod proto;
+
+use proto::{
+    metric, number_data_point, ExportMetricsServiceRequest, ExportMetricsServiceResponse, Gauge,
+    Histogram, HistogramDataPoint, InstrumentationScope, KeyValue, Metric, NumberDataPoint,
+    Resource, ResourceMetrics, ScopeMetrics, Sum, AGGREGATION_TEMPORALITY_CUMULATIVE,
+};
+
+const EXPORT_PATH: &str = "/opentelemetry.proto.collector.metrics.v1.MetricsService/Export";
+const HTTP_EXPORT_PATH: &str = "/v1/metrics";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum OtlpProtocol {
+    Grpc,
+    HttpProtobuf,
+}
+
+/// Configuration of the OTLP exporter.
+#[derive(Debug, Clone)]
+pub(crate) struct OtlpConfig {
+    pub endpoint: String,
+    pub protocol: OtlpProtocol,
+    pub interval: Duration,
+    pub headers: Vec<(String, String)>,
+    pub resource_attributes: Vec<(String, String)>,
+    pub ca_cert: Option<PathBuf>,
+}
+
+impl OtlpConfig {
+    /// Builds the exporter configuration, or `None` if no OTLP endpoint is configured.
+    pub(crate) fn from_args(args: &Args) -> anyhow::Result<Option<Self>> {
+        let Some(endpoint) = args.otlp_endpoint.as_ref() else {
+            return Ok(None);
+        };
+
+        let mut resource_attributes = vec![("service.name".to_string(), "eva-agent".to_string())];
+        if let Ok(node_name) = std::env::var(NODE_NAME_ENV) {
+            resource_attributes.push(("k8s.node.name".to_string(), node_name));
+        }
+        if let Some(cluster_name) = args.cluster_name.as_ref() {
+            resource_attributes.push(("k8s.cluster.name".to_string(), cluster_name.clone()));
+        }
+        for (key, value) in parse_key_values(&args.otlp_resource_attributes)? {
+            resource_attributes.retain(|(k, _)| *k != key);
+            resource_attributes.push((key, value));
+        }
+
+        Ok(Some(Self {
+            endpoint: endpoint.trim_end_matches('/').to_string(),
+            protocol: args.otlp_protocol,
+            interval: Duration::from_secs(args.otlp_interval),
+            headers: parse_key_values(&args.otlp_headers)?,
+            resource_attributes,
+            ca_cert: args.otlp_ca_cert.clone(),
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Transport {
+    Grpc(Channel),
+    Http(Box<HttpClient<HttpsConnector<HttpConnector>, Full<Bytes>>>),
+}
+
+/// Periodically pushes the metric families of the collector to an OTLP receiver, such
+/// as the OpenTelemetry collector.
+#[derive(Debug, Clone)]
+pub(crate) struct OtlpExporter {
+    config: OtlpConfig,
+    transport: Transport,
+}
+
+impl OtlpExporter {
+    pub(crate) fn new(config: OtlpConfig) -> anyhow::Result<Self> {
+        let transport = match config.protocol {
+            OtlpProtocol::Grpc => {
+                let mut endpoint = Endpoint::from_str(&config.endpoint)?
+                    .timeout(Duration::from_secs(EXPORT_TIMEOUT));
+                if config.endpoint.starts_with("https://") {
+                    let mut tls_config = ClientTlsConfig::new();
+                    if let Some(ca_cert) = config.ca_cert.as_ref() {
+                        tls_config = tls_config
+                            .ca_certificate(Certificate::from_pem(std::fs::read(ca_cert)?));
+                    }
+                    endpoint = endpoint.tls_config(tls_config)?;
+                }
+                Transport::Grpc(endpoint.connect_lazy())
+            }
+            OtlpProtocol::HttpProtobuf => Transport::Http(Box::new(
+                HttpClient::builder(TokioExecutor::new())
+                    .build(https_connector(config.ca_cert.as_deref())?),
+            )),
+        };
+        Ok(Self { config, transport })
+    }
+
+    pub(crate) async fn start(
+        &self,
+        collector: Collector,
+        mut shutdown_rx: Receiver<ShutdownSignal>,
+    ) {
+        info!(
+            "Exporting metrics over OTLP to {} every {:?}",
+            self.config.endpoint, self.config.interval
+        );
+        let mut interval = time::interval(self.config.interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    if let Err(e) = self.export(&collector.families()).await {
+                        warn!("Failed to export metrics over OTLP: {:?}", e);
+                    }
+                }
+                Ok(signal) = shutdown_rx.recv() => {
+                    if let ShutdownSignal::All = signal {
+                        info!("Received shutdown signal, stopping OTLP exporter.");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) async fn export(&self, families: &[MetricFamily]) -> anyhow::Result<()> {
+        let request = self.build_request(families, SystemTime::now());
+        match &self.transport {
+            Transport::Grpc(channel) => self.export_grpc(channel.clone(), request).await,
+            Transport::Http(client) => self.export_http(client, request).await,
+        }
+    }
+
+    fn build_request(
+        &self,
+        families: &[MetricFamily],
+        now: SystemTime,
+    ) -> ExportMetricsServiceRequest {
+        let time_unix_nano = unix_nanos(now);
+        let metrics = families
+            .iter()
+            .map(|family| {
+                let data = match family.kind {
+                    MetricKind::Gauge => metric::Data::Gauge(Gauge {
+                        data_points: number_data_points(family, time_unix_nano),
+                    }),
+                    MetricKind::Counter => metric::Data::Sum(Sum {
+                        data_points: number_data_points(family, time_unix_nano),
+                        aggregation_temporality: AGGREGATION_TEMPORALITY_CUMULATIVE,
+                        is_monotonic: true,
+                    }),
+                    MetricKind::Histogram => metric::Data::Histogram(Histogram {
+                        data_points: histogram_data_points(family, time_unix_nano),
+                        aggregation_temporality: AGGREGATION_TEMPORALITY_CUMULATIVE,
+                    }),
+                };
+                Metric {
+                    name: family.name.clone(),
+                    description: family.help.clone(),
+                    unit: family
+                        .unit
+                        .as_ref()
+                        .map(|unit| ucum_unit(unit.as_str()))
+                        .unwrap_or_default(),
+                    data: Some(data),
+                }
+            })
+            .collect();
+
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: self
+                        .config
+                        .resource_attributes
+                        .iter()
+                        .map(|(key, value)| KeyValue::new(key, value))
+                        .collect(),
+                }),
+                scope_metrics: vec![ScopeMetrics {
+                    scope: Some(InstrumentationScope {
+                        name: "eva-agent".to_string(),
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                    }),
+                    metrics,
+                }],
+            }],
+        }
+    }
+
+    async fn export_grpc(
+        &self,
+        channel: Channel,
+        request: ExportMetricsServiceRequest,
+    ) -> anyhow::Result<()> {
+        let mut request = tonic::Request::new(request);
+        for (key, value) in self.config.headers.iter() {
+            request.metadata_mut().insert(
+                MetadataKey::from_bytes(key.to_lowercase().as_bytes())?,
+                AsciiMetadataValue::try_from(value.as_str())?,
+            );
+        }
+        let mut grpc = tonic::client::Grpc::new(channel);
+        grpc.ready().await?;
+        let response: tonic::Response<ExportMetricsServiceResponse> = grpc
+            .unary(
+                request,
+                PathAndQuery::from_static(EXPORT_PATH),
+                ProstCodec::default(),
+            )
+            .await?;
+        log_partial_success(response.into_inner());
+        Ok(())
+    }
+
+    async fn export_http(
+        &self,
+        client: &HttpClient<HttpsConnector<HttpConnector>, Full<Bytes>>,
+        request: ExportMetricsServiceRequest,
+    ) -> anyhow::Result<()> {
+        let uri = if self.config.endpoint.ends_with(HTTP_EXPORT_PATH) {
+            self.config.endpoint.clone()
+        } else {
+            format!("{}{}", self.config.endpoint, HTTP_EXPORT_PATH)
+        };
+        let mut builder = HttpRequest::post(uri).header("content-type", "application/x-protobuf");
+        for (key, value) in self.config.headers.iter() {
+            builder = builder.header(key.as_str(), value.as_str());
+        }
+        let http_request = builder.body(Full::new(Bytes::from(request.encode_to_vec())))?;
+
+        let response = time::timeout(
+            Duration::from_secs(EXPORT_TIMEOUT),
+            client.request(http_request),
+        )
+        .await??;
+        let status = response.status();
+        let body = response.into_body().collect().await?.to_bytes();
+        if !status.is_success() {
+            return Err(anyhow::anyhow!(
+                "OTLP receiver responded with {}: {}",
+                status,
+                String::from_utf8_lossy(&body)
+            ));
+        }
+        if let Ok(response) = ExportMetricsServiceResponse::decode(body) {
+            log_partial_success(response);
+        }
+        Ok(())
+    }
+}
+
+fn log_partial_success(response: ExportMetricsServiceResponse) {
+    match response.partial_success {
+        Some(partial) if partial.rejected_data_points > 0 => warn!(
+            "OTLP receiver rejected {} data points: {}",
+            partial.rejected_data_points, partial.error_message
+        ),
+        _ => debug!("Exported metrics over OTLP"),
+    }
+}
+
+fn attributes(labels: &[(String, String)]) -> Vec<KeyValue> {
+    labels
+        .iter()
+        .map(|(key, value)| KeyValue::new(key, value))
+        .collect()
+}
+
+fn number_data_points(family: &MetricFamily, time_unix_nano: u64) -> Vec<NumberDataPoint> {
+    family
+        .samples
+        .iter()
+        .map(|sample| NumberDataPoint {
+            attributes: attributes(&sample.labels),
+            start_time_unix_nano: sample.created.map(unix_nanos).unwrap_or_default(),
+            time_unix_nano,
+            value: Some(number_data_point::Value::AsDouble(sample.value)),
+        })
+        .collect()
+}
+
+fn histogram_data_points(family: &MetricFamily, time_unix_nano: u64) -> Vec<HistogramDataPoint> {
+    family
+        .samples
+        .iter()
+        .filter_map(|sample| {
+            let histogram = sample.histogram.as_ref()?;
+            Some(HistogramDataPoint {
+                attributes: attributes(&sample.labels),
+                start_time_unix_nano: sample.created.map(unix_nanos).unwrap_or_default(),
+                time_unix_nano,
+                count: histogram.count,
+                sum: Some(histogram.sum),
+                bucket_counts: histogram.buckets.iter().map(|(_, count)| *count).collect(),
+                // The `+Inf` bucket is implied by OTLP.
+                explicit_bounds: histogram
+                    .buckets
+                    .iter()
+                    .map(|(bound, _)| *bound)
+                    .filter(|bound| *bound < f64::MAX)
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+/// Maps the Prometheus unit suffixes to UCUM units, as expected by OTLP.
+fn ucum_unit(unit: &str) -> String {
+    match unit {
+        "seconds" => "s",
+        "bytes" => "By",
+        "ratios" => "1",
+        "meters" => "m",
+        "grams" => "g",
+        "volts" => "V",
+        "amperes" => "A",
+        "joules" => "J",
+        "celsius" => "Cel",
+        other => other,
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::{Request, Response};
+    use hyper_util::rt::TokioIo;
+    use prometheus_client::registry::Unit;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::managers::metrics::Sample;
+
+    #[tokio::test]
+    async fn test_export_http_protobuf() {
+        // A stand-in for an OTLP receiver, forwarding every decoded request.
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(move |req: Request<Incoming>| {
+                let tx = tx.clone();
+                async move {
+                    let path = req.uri().path().to_string();
+                    let body = req.into_body().collect().await.unwrap().to_bytes();
+                    let request = ExportMetricsServiceRequest::decode(body).unwrap();
+                    tx.send((path, request)).unwrap();
+                    let response = ExportMetricsServiceResponse::default().encode_to_vec();
+                    Ok::<_, Infallible>(Response::new(Full::new(Bytes::from(response))))
+                }
+            });
+            http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+                .unwrap();
+        });
+
+        let exporter = OtlpExporter::new(OtlpConfig {
+            endpoint: format!("http://{}", addr),
+            protocol: OtlpProtocol::HttpProtobuf,
+            interval: Duration::from_secs(15),
+            headers: vec![],
+            resource_attributes: vec![("k8s.cluster.name".to_string(), "edge".to_string())],
+            ca_cert: None,
+        })
+        .unwrap();
+        let mut family = MetricFamily::new(
+            "connection_observed",
+            "total bytes_sent value of connections observed",
+            Some(Unit::Bytes),
+            MetricKind::Gauge,
+        );
+        family
+            .samples
+            .push(Sample::new([("role", "client".to_string())], 42.0));
+        exporter.export(&[family]).await.unwrap();
+
+        let (path, request) = rx.recv().await.unwrap();
+        assert_eq!(path, HTTP_EXPORT_PATH);
+        let resource_metrics = &request.resource_metrics[0];
+        assert_eq!(
+            resource_metrics.resource.as_ref().unwrap().attributes,
+            vec![KeyValue::new("k8s.cluster.name", "edge")]
+        );
+        let metric = &resource_metrics.scope_metrics[0].metrics[0];
+        assert_eq!(metric.name, "connection_observed");
+        assert_eq!(metric.unit, "By");
+        let Some(metric::Data::Gauge(gauge)) = metric.data.as_ref() else {
+            panic!("Expected a gauge");
+        };
+        assert_eq!(
+            gauge.data_points[0].attributes,
+            vec![KeyValue::new("role", "client")]
+        );
+        assert_eq!(
+            gauge.data_points[0].value,
+            Some(number_data_point::Value::AsDouble(42.0))
+        );
+    }
+}
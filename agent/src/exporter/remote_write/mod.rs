@@ -0,0 +1,399 @@
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper::{Request, StatusCode};
+use hyper_rustls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client as HttpClient;
+use hyper_util::rt::TokioExecutor;
+use log::{debug, info, warn};
+use prost::Message;
+use tokio::sync::broadcast::Receiver;
+use tokio::time;
+
+use crate::collector::Collector;
+use crate::common::constants::EXPORT_TIMEOUT;
+use crate::exporter::{https_connector, parse_key_values};
+use crate::managers::metrics::MetricFamily;
+use crate::progs::types::ShutdownSignal;
+use crate::Args;
+
+pub(crate) mod proto;
+pub(crate) mod queue;
+
+use proto::{Label, TimeSeries, WriteRequest};
+use queue::Queue;
+
+/// Upper bound of the delay between two attempts to send a failed batch.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Configuration of the Prometheus remote-write exporter.
+#[derive(Debug, Clone)]
+pub(crate) struct RemoteWriteConfig {
+    pub url: String,
+    pub interval: Duration,
+    pub headers: Vec<(String, String)>,
+    pub external_labels: Vec<(String, String)>,
+    pub queue_capacity: usize,
+    pub queue_path: Option<PathBuf>,
+    pub ca_cert: Option<PathBuf>,
+}
+
+impl RemoteWriteConfig {
+    /// Builds the exporter configuration, or `None` if no remote-write URL is configured.
+    pub(crate) fn from_args(args: &Args) -> anyhow::Result<Option<Self>> {
+        let Some(url) = args.remote_write_url.as_ref() else {
+            return Ok(None);
+        };
+        Ok(Some(Self {
+            url: url.clone(),
+            interval: Duration::from_secs(args.remote_write_interval),
+            headers: parse_key_values(&args.remote_write_headers)?,
+            external_labels: parse_key_values(&args.remote_write_external_labels)?,
+            queue_capacity: args.remote_write_queue_capacity,
+            queue_path: args.remote_write_queue_path.clone(),
+            ca_cert: args.remote_write_ca_cert.clone(),
+        }))
+    }
+}
+
+/// Periodically pushes the metric families of the collector with Prometheus
+/// remote-write. Batches that cannot be sent are queued and retried with exponential
+/// backoff, dropping the oldest ones once the queue is full.
+#[derive(Debug)]
+pub(crate) struct RemoteWriteExporter {
+    config: RemoteWriteConfig,
+    client: HttpClient<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    queue: Queue,
+    backoff: Duration,
+    next_attempt: Option<Instant>,
+}
+
+enum SendError {
+    /// The batch may succeed later, e.g. on network errors, 5xx or 429 responses.
+    Retryable(anyhow::Error),
+    /// The receiver rejected the batch, so it is dropped.
+    Rejected(anyhow::Error),
+}
+
+impl RemoteWriteExporter {
+    pub(crate) fn new(config: RemoteWriteConfig) -> anyhow::Result<Self> {
+        let connector = https_connector(config.ca_cert.as_deref())?;
+        let queue = match config.queue_path.as_ref() {
+            Some(path) => Queue::on_disk(path, config.queue_capacity)?,
+            None => Queue::in_memory(config.queue_capacity),
+        };
+        Ok(Self {
+            client: HttpClient::builder(TokioExecutor::new()).build(connector),
+            backoff: config.interval,
+            config,
+            queue,
+            next_attempt: None,
+        })
+    }
+
+    pub(crate) async fn start(
+        mut self,
+        collector: Collector,
+        mut shutdown_rx: Receiver<ShutdownSignal>,
+    ) {
+        info!(
+            "Pushing metrics with remote-write to {} every {:?}",
+            self.config.url, self.config.interval
+        );
+        let mut interval = time::interval(self.config.interval);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    let families = collector.families();
+                    if let Err(e) = self.enqueue(&families, SystemTime::now()) {
+                        warn!("Failed to queue remote-write batch: {:?}", e);
+                    }
+                }
+                Ok(signal) = shutdown_rx.recv() => {
+                    if let ShutdownSignal::All = signal {
+                        info!("Received shutdown signal, stopping remote-write exporter.");
+                        break;
+                    }
+                }
+            }
+            self.flush().await;
+        }
+    }
+
+    fn enqueue(&mut self, families: &[MetricFamily], now: SystemTime) -> anyhow::Result<()> {
+        let request = self.build_request(families, now);
+        let batch = snap::raw::Encoder::new().compress_vec(&request.encode_to_vec())?;
+        if self.queue.push(Bytes::from(batch))? {
+            warn!("Remote-write queue is full, dropped the oldest batch");
+        }
+        Ok(())
+    }
+
+    fn build_request(&self, families: &[MetricFamily], now: SystemTime) -> WriteRequest {
+        let timestamp = now
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as i64;
+        let mut timeseries = vec![];
+        for family in families {
+            let name = family.sample_name();
+            for sample in family.samples.iter() {
+                let Some(histogram) = sample.histogram.as_ref() else {
+                    timeseries.push(self.time_series(
+                        &name,
+                        &sample.labels,
+                        sample.value,
+                        timestamp,
+                    ));
+                    continue;
+                };
+                // Histograms are written as classic `_bucket`, `_sum` and `_count` series.
+                let mut cumulative = 0;
+                for (bound, count) in histogram.buckets.iter() {
+                    cumulative += count;
+                    let mut labels = sample.labels.clone();
+                    labels.push(("le".to_string(), format_bound(*bound)));
+                    timeseries.push(self.time_series(
+                        &format!("{}_bucket", name),
+                        &labels,
+                        cumulative as f64,
+                        timestamp,
+                    ));
+                }
+                timeseries.push(self.time_series(
+                    &format!("{}_sum", name),
+                    &sample.labels,
+                    histogram.sum,
+                    timestamp,
+                ));
+                timeseries.push(self.time_series(
+                    &format!("{}_count", name),
+                    &sample.labels,
+                    histogram.count as f64,
+                    timestamp,
+                ));
+            }
+        }
+        WriteRequest { timeseries }
+    }
+
+    fn time_series(
+        &self,
+        name: &str,
+        labels: &[(String, String)],
+        value: f64,
+        timestamp: i64,
+    ) -> TimeSeries {
+        let mut labels: Vec<Label> = labels
+            .iter()
+            .chain(self.config.external_labels.iter())
+            .map(|(name, value)| Label {
+                name: name.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        labels.push(Label {
+            name: "__name__".to_string(),
+            value: name.to_string(),
+        });
+        labels.sort_by(|a, b| a.name.cmp(&b.name));
+        labels.dedup_by(|a, b| a.name == b.name);
+        TimeSeries {
+            labels,
+            samples: vec![proto::Sample { value, timestamp }],
+        }
+    }
+
+    /// Sends queued batches in order until the queue is empty or a batch has to be
+    /// retried later.
+    async fn flush(&mut self) {
+        if self.next_attempt.is_some_and(|at| Instant::now() < at) {
+            return;
+        }
+        loop {
+            let batch = match self.queue.front() {
+                Ok(Some(batch)) => batch,
+                Ok(None) => break,
+                Err(e) => {
+                    warn!("Failed to read remote-write batch, dropping it: {:?}", e);
+                    let _ = self.queue.pop();
+                    continue;
+                }
+            };
+            match self.send(batch).await {
+                Ok(()) => {
+                    debug!("Pushed remote-write batch, {} left", self.queue.len() - 1);
+                    self.backoff = self.config.interval;
+                    self.next_attempt = None;
+                }
+                Err(SendError::Rejected(e)) => {
+                    warn!("Remote-write batch rejected, dropping it: {:?}", e);
+                }
+                Err(SendError::Retryable(e)) => {
+                    warn!(
+                        "Failed to push remote-write batch, retrying in {:?}: {:?}",
+                        self.backoff, e
+                    );
+                    self.next_attempt = Some(Instant::now() + self.backoff);
+                    self.backoff = (self.backoff * 2).min(MAX_BACKOFF);
+                    break;
+                }
+            }
+            if let Err(e) = self.queue.pop() {
+                warn!("Failed to remove remote-write batch: {:?}", e);
+                break;
+            }
+        }
+    }
+
+    async fn send(&self, batch: Bytes) -> Result<(), SendError> {
+        let mut builder = Request::post(self.config.url.as_str())
+            .header("content-encoding", "snappy")
+            .header("content-type", "application/x-protobuf")
+            .header("x-prometheus-remote-write-version", "0.1.0")
+            .header(
+                "user-agent",
+                format!("eva-agent/{}", env!("CARGO_PKG_VERSION")),
+            );
+        for (key, value) in self.config.headers.iter() {
+            builder = builder.header(key.as_str(), value.as_str());
+        }
+        let request = builder
+            .body(Full::new(batch))
+            .map_err(|e| SendError::Rejected(e.into()))?;
+
+        let response = time::timeout(
+            Duration::from_secs(EXPORT_TIMEOUT),
+            self.client.request(request),
+        )
+        .await
+        .map_err(|e| SendError::Retryable(e.into()))?
+        .map_err(|e| SendError::Retryable(e.into()))?;
+        let status = response.status();
+        if status.is_success() {
+            return Ok(());
+        }
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map(|body| String::from_utf8_lossy(&body.to_bytes()).to_string())
+            .unwrap_or_default();
+        let e = anyhow::anyhow!("Receiver responded with {}: {}", status, body);
+        if status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS {
+            Err(SendError::Retryable(e))
+        } else {
+            Err(SendError::Rejected(e))
+        }
+    }
+}
+
+fn format_bound(bound: f64) -> String {
+    if bound == f64::MAX {
+        "+Inf".to_string()
+    } else {
+        bound.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::managers::metrics::{MetricKind, Sample};
+
+    #[tokio::test]
+    async fn test_remote_write_retries_failed_batches() {
+        // A stand-in for a remote-write receiver that fails the first request.
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let requests = Arc::new(AtomicUsize::new(0));
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let tx = tx.clone();
+                let requests = requests.clone();
+                let service = service_fn(move |req: Request<Incoming>| {
+                    let tx = tx.clone();
+                    let requests = requests.clone();
+                    async move {
+                        let body = req.into_body().collect().await.unwrap().to_bytes();
+                        let status = if requests.fetch_add(1, Ordering::SeqCst) == 0 {
+                            StatusCode::SERVICE_UNAVAILABLE
+                        } else {
+                            let body = snap::raw::Decoder::new().decompress_vec(&body).unwrap();
+                            tx.send(WriteRequest::decode(body.as_slice()).unwrap())
+                                .unwrap();
+                            StatusCode::NO_CONTENT
+                        };
+                        let mut response = Response::new(Full::new(Bytes::new()));
+                        *response.status_mut() = status;
+                        Ok::<_, Infallible>(response)
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+
+        let mut exporter = RemoteWriteExporter::new(RemoteWriteConfig {
+            url: format!("http://{}/api/v1/write", addr),
+            interval: Duration::from_millis(10),
+            headers: vec![],
+            external_labels: vec![("cluster".to_string(), "edge".to_string())],
+            queue_capacity: 10,
+            queue_path: None,
+            ca_cert: None,
+        })
+        .unwrap();
+        let mut family = MetricFamily::new(
+            "ebpf_map_entries",
+            "current number of entries in a bound eBPF map",
+            None,
+            MetricKind::Gauge,
+        );
+        family
+            .samples
+            .push(Sample::new([("program", "service_map".to_string())], 3.0));
+        exporter.enqueue(&[family], UNIX_EPOCH).unwrap();
+
+        exporter.flush().await;
+        assert_eq!(exporter.queue.len(), 1);
+        time::sleep(Duration::from_millis(20)).await;
+        exporter.flush().await;
+        assert_eq!(exporter.queue.len(), 0);
+
+        let request = rx.recv().await.unwrap();
+        let labels: Vec<_> = request.timeseries[0]
+            .labels
+            .iter()
+            .map(|label| (label.name.as_str(), label.value.as_str()))
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                ("__name__", "ebpf_map_entries"),
+                ("cluster", "edge"),
+                ("program", "service_map")
+            ]
+        );
+        assert_eq!(request.timeseries[0].samples[0].value, 3.0);
+    }
+}
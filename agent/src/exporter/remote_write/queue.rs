@@ -0,0 +1,148 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use hyper::body::Bytes;
+use log::warn;
+
+const BATCH_EXTENSION: &str = "sz";
+
+#[derive(Debug)]
+enum Storage {
+    Memory(VecDeque<Bytes>),
+    /// Batches are kept as one file per batch, named by their sequence number so that
+    /// they survive restarts of the agent in order.
+    Disk {
+        dir: PathBuf,
+        files: VecDeque<(u64, PathBuf)>,
+    },
+}
+
+/// A bounded FIFO queue of encoded remote-write batches. Once full, the oldest batch is
+/// dropped to make room for the newest.
+#[derive(Debug)]
+pub(crate) struct Queue {
+    storage: Storage,
+    capacity: usize,
+    next_seq: u64,
+}
+
+impl Queue {
+    pub(crate) fn in_memory(capacity: usize) -> Self {
+        Self {
+            storage: Storage::Memory(VecDeque::new()),
+            capacity: capacity.max(1),
+            next_seq: 0,
+        }
+    }
+
+    /// Opens the queue in `dir`, picking up the batches left by a previous run.
+    pub(crate) fn on_disk(dir: &Path, capacity: usize) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let mut files = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(BATCH_EXTENSION) {
+                continue;
+            }
+            let seq = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok());
+            match seq {
+                Some(seq) => files.push((seq, path)),
+                None => warn!("Ignoring unexpected file {} in queue", path.display()),
+            }
+        }
+        files.sort();
+        let next_seq = files.last().map(|(seq, _)| seq + 1).unwrap_or_default();
+        let mut queue = Self {
+            storage: Storage::Disk {
+                dir: dir.to_path_buf(),
+                files: files.into(),
+            },
+            capacity: capacity.max(1),
+            next_seq,
+        };
+        while queue.len() > queue.capacity {
+            queue.pop()?;
+        }
+        Ok(queue)
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        match &self.storage {
+            Storage::Memory(batches) => batches.len(),
+            Storage::Disk { files, .. } => files.len(),
+        }
+    }
+
+    /// Appends a batch, and returns whether the oldest batch had to be dropped for it.
+    pub(crate) fn push(&mut self, batch: Bytes) -> anyhow::Result<bool> {
+        let dropped = self.len() >= self.capacity;
+        if dropped {
+            self.pop()?;
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        match &mut self.storage {
+            Storage::Memory(batches) => batches.push_back(batch),
+            Storage::Disk { dir, files } => {
+                let path = dir.join(format!("{:020}.{}", seq, BATCH_EXTENSION));
+                fs::write(&path, &batch)?;
+                files.push_back((seq, path));
+            }
+        }
+        Ok(dropped)
+    }
+
+    pub(crate) fn front(&self) -> anyhow::Result<Option<Bytes>> {
+        match &self.storage {
+            Storage::Memory(batches) => Ok(batches.front().cloned()),
+            Storage::Disk { files, .. } => match files.front() {
+                Some((_, path)) => Ok(Some(Bytes::from(fs::read(path)?))),
+                None => Ok(None),
+            },
+        }
+    }
+
+    pub(crate) fn pop(&mut self) -> anyhow::Result<()> {
+        match &mut self.storage {
+            Storage::Memory(batches) => {
+                batches.pop_front();
+            }
+            Storage::Disk { files, .. } => {
+                if let Some((_, path)) = files.pop_front() {
+                    fs::remove_file(path)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_queue_drops_oldest_and_survives_reopen() {
+        let dir = std::env::temp_dir().join(format!("eva-remote-write-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut queue = Queue::on_disk(&dir, 2).unwrap();
+        assert!(!queue.push(Bytes::from_static(b"1")).unwrap());
+        assert!(!queue.push(Bytes::from_static(b"2")).unwrap());
+        assert!(queue.push(Bytes::from_static(b"3")).unwrap());
+        drop(queue);
+
+        let mut queue = Queue::on_disk(&dir, 2).unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.front().unwrap(), Some(Bytes::from_static(b"2")));
+        queue.pop().unwrap();
+        queue.push(Bytes::from_static(b"4")).unwrap();
+        assert_eq!(queue.front().unwrap(), Some(Bytes::from_static(b"3")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
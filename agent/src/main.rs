@@ -2,11 +2,15 @@ use std::path::PathBuf;
 
 use clap::Parser;
 
+use crate::exporter::otlp::OtlpProtocol;
+use crate::managers::cardinality::CardinalityStrategy;
+use crate::managers::metrics::MetricNamespace;
 use crate::server::serve;
 use crate::utils::init_env;
 
 mod collector;
 mod common;
+mod exporter;
 mod managers;
 mod progs;
 mod server;
@@ -24,9 +28,63 @@ pub(crate) struct Args {
     /// Optional: Path under which to expose metrics.
     #[clap(long, verbatim_doc_comment, default_value = "/metrics")]
     pub(crate) metrics_path: String,
+    /// Optional: How the metrics of each program are told apart: by a `program` label,
+    /// by prefixing metric names with the program name, or not at all. The metrics of
+    /// a single program are also served under <metrics-path>/<program>.
+    #[clap(long, verbatim_doc_comment, value_enum, default_value = "label")]
+    pub(crate) metric_namespace: MetricNamespace,
+    /// Optional: Maximum number of label sets of program metrics across all programs,
+    /// 0 for no limit. A label set, such as a service map edge, counts once however
+    /// many metrics it has.
+    #[clap(long, verbatim_doc_comment, default_value_t = 0)]
+    pub(crate) max_series: usize,
+    /// Optional: Maximum number of label sets of single programs.
+    /// Example: --program-max-series service_map=5000
+    #[clap(long, verbatim_doc_comment, value_delimiter = ',')]
+    pub(crate) program_max_series: Vec<String>,
+    /// Optional: What to do with the label sets of a program beyond its limit: drop them,
+    /// aggregate them into one label set labelled `other`, or always drop the labels
+    /// given by --cardinality-drop-labels and drop what is still beyond the limit.
+    #[clap(long, verbatim_doc_comment, value_enum, default_value = "drop")]
+    pub(crate) cardinality_strategy: CardinalityStrategy,
+    /// Optional: Labels removed from program metrics by the drop-labels strategy.
+    /// Example: --cardinality-drop-labels server_port,client_name
+    #[clap(long, verbatim_doc_comment, value_delimiter = ',')]
+    pub(crate) cardinality_drop_labels: Vec<String>,
+    /// Optional: Programs whose Failed state does not fail the /readyz check.
+    /// Example: --tolerated-failed-programs conn-tracer,tcp-stats
+    #[clap(long, verbatim_doc_comment, value_delimiter = ',')]
+    pub(crate) tolerated_failed_programs: Vec<String>,
+    /// Optional: PEM encoded certificate to serve the metrics server over TLS.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) metrics_tls_cert: Option<PathBuf>,
+    /// Optional: PEM encoded private key of the metrics server certificate.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) metrics_tls_key: Option<PathBuf>,
+    /// Optional: PEM encoded CA certificates used to verify client certificates.
+    /// Requests for metrics must then present a client certificate signed by it,
+    /// unless they pass --metrics-token-auth.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) metrics_tls_client_ca: Option<PathBuf>,
+    /// Optional: Authorize requests for metrics by their bearer token with a
+    /// TokenReview and a SubjectAccessReview for `get` on the metrics path, like
+    /// kube-rbac-proxy does.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) metrics_token_auth: bool,
     /// Optional: Location of the agent unix socket.
     #[clap(long, verbatim_doc_comment, default_value = "/run/eva/agent.sock")]
     pub(crate) agent_socket_path: PathBuf,
+    /// Optional: TOML policy authorizing callers of the agent unix socket by their
+    /// peer credentials. Without a policy, every caller may use every RPC.
+    /// Example policy allowing root everything and gid 2000 read-only access:
+    ///   [[rules]]
+    ///   uid = 0
+    ///   methods = ["*"]
+    ///   [[rules]]
+    ///   gid = 2000
+    ///   methods = ["List", "Get"]
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) agent_socket_policy: Option<PathBuf>,
     /// Optional: Location of the bpfman unix socket.
     #[clap(
         long,
@@ -34,6 +92,102 @@ pub(crate) struct Args {
         default_value = "/run/bpfman-sock/bpfman.sock"
     )]
     pub(crate) bpfman_socket_path: String,
+    /// Optional: socket address to listen on for the agent gRPC API over TCP.
+    /// Connections are served with mutual TLS, so --agent-tls-cert, --agent-tls-key
+    /// and --agent-tls-client-ca are required as well.
+    /// Example: --agent-tcp-addr 0.0.0.0:50051
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) agent_tcp_addr: Option<String>,
+    /// Optional: PEM encoded server certificate for the TCP listener.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) agent_tls_cert: Option<PathBuf>,
+    /// Optional: PEM encoded private key of the server certificate.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) agent_tls_key: Option<PathBuf>,
+    /// Optional: PEM encoded CA certificates used to verify client certificates.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) agent_tls_client_ca: Option<PathBuf>,
+    /// Optional: Subject alternative names (DNS, URI or IP) of the client certificates
+    /// allowed to call the TCP listener. Any client certificate signed by the client CA
+    /// is allowed if empty.
+    /// Example: --agent-tls-allowed-sans controller.example.com,spiffe://cluster/ns/ops/sa/ctl
+    #[clap(long, verbatim_doc_comment, value_delimiter = ',')]
+    pub(crate) agent_tls_allowed_sans: Vec<String>,
+    /// Optional: Location of the audit log recording every mutating request, such as
+    /// Load, Unload and PullBytecode, as JSON lines.
+    #[clap(long, verbatim_doc_comment, default_value = "/var/log/eva/audit.log")]
+    pub(crate) audit_log_path: PathBuf,
+    /// Optional: Size in bytes after which the audit log is rotated.
+    #[clap(long, verbatim_doc_comment, default_value = "10485760")]
+    pub(crate) audit_log_max_size: u64,
+    /// Optional: Number of rotated audit log files to keep.
+    #[clap(long, verbatim_doc_comment, default_value = "5")]
+    pub(crate) audit_log_max_files: usize,
+    /// Optional: Name of the cluster the agent runs in, attached to pushed metrics.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) cluster_name: Option<String>,
+    /// Optional: OTLP receiver to push the metrics served on the metrics server to, such
+    /// as an OpenTelemetry collector. Metrics are only pushed if it is set.
+    /// Example: --otlp-endpoint http://otel-collector.observability:4317
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) otlp_endpoint: Option<String>,
+    /// Optional: Protocol of the OTLP receiver.
+    #[clap(long, verbatim_doc_comment, value_enum, default_value = "grpc")]
+    pub(crate) otlp_protocol: OtlpProtocol,
+    /// Optional: Interval in seconds between two pushes of metrics over OTLP.
+    #[clap(
+        long,
+        verbatim_doc_comment,
+        default_value = "15",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub(crate) otlp_interval: u64,
+    /// Optional: Headers sent with every OTLP request.
+    /// Example: --otlp-headers x-scope-orgid=edge,api-key=secret
+    #[clap(long, verbatim_doc_comment, value_delimiter = ',')]
+    pub(crate) otlp_headers: Vec<String>,
+    /// Optional: Resource attributes of pushed metrics, in addition to service.name,
+    /// k8s.node.name and k8s.cluster.name.
+    /// Example: --otlp-resource-attributes deployment.environment=prod
+    #[clap(long, verbatim_doc_comment, value_delimiter = ',')]
+    pub(crate) otlp_resource_attributes: Vec<String>,
+    /// Optional: PEM encoded CA certificates used to verify an https:// OTLP receiver.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) otlp_ca_cert: Option<PathBuf>,
+    /// Optional: Prometheus remote-write URL to push the metrics served on the metrics
+    /// server to, for clusters where it cannot be scraped. Metrics are only pushed if it
+    /// is set.
+    /// Example: --remote-write-url https://prometheus.example.com/api/v1/write
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) remote_write_url: Option<String>,
+    /// Optional: Interval in seconds between two remote-write batches.
+    #[clap(
+        long,
+        verbatim_doc_comment,
+        default_value = "15",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    pub(crate) remote_write_interval: u64,
+    /// Optional: Headers sent with every remote-write request.
+    /// Example: --remote-write-headers x-scope-orgid=edge
+    #[clap(long, verbatim_doc_comment, value_delimiter = ',')]
+    pub(crate) remote_write_headers: Vec<String>,
+    /// Optional: Labels added to every pushed series.
+    /// Example: --remote-write-external-labels cluster=edge-1,region=eu
+    #[clap(long, verbatim_doc_comment, value_delimiter = ',')]
+    pub(crate) remote_write_external_labels: Vec<String>,
+    /// Optional: Number of batches kept while the remote-write URL is unreachable.
+    /// The oldest batch is dropped once the queue is full.
+    #[clap(long, verbatim_doc_comment, default_value = "240")]
+    pub(crate) remote_write_queue_capacity: usize,
+    /// Optional: Directory to keep queued remote-write batches in, so that they
+    /// survive restarts. Batches are kept in memory if not set.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) remote_write_queue_path: Option<PathBuf>,
+    /// Optional: PEM encoded CA certificates used to verify an https:// remote-write
+    /// URL instead of the system roots.
+    #[clap(long, verbatim_doc_comment)]
+    pub(crate) remote_write_ca_cert: Option<PathBuf>,
 }
 
 #[tokio::main]
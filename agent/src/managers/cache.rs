@@ -1,10 +1,12 @@
+use std::net::IpAddr;
 use std::sync::Arc;
 
-use ahash::AHashMap;
+use ahash::{AHashMap, AHashSet};
 use futures::{StreamExt, TryStreamExt};
 use k8s_openapi::api::apps::v1::{DaemonSet, Deployment, ReplicaSet, StatefulSet};
 use k8s_openapi::api::batch::v1::{CronJob, Job};
-use k8s_openapi::api::core::v1::{Node, Pod, Service};
+use k8s_openapi::api::core::v1::{Container, Node, Pod, PodSpec, Service, ServiceSpec};
+use k8s_openapi::api::discovery::v1::EndpointSlice;
 use k8s_openapi::apimachinery::pkg::apis::meta::v1::OwnerReference;
 use kube::runtime::reflector::store::Writer;
 use kube::runtime::reflector::{ObjectRef, Store};
@@ -13,9 +15,11 @@ use kube::{
     runtime::{predicates, reflector, watcher, WatchStreamExt},
     Client, ResourceExt,
 };
-use log::{debug, info};
+use log::{debug, info, warn};
 use parking_lot::RwLock;
 
+use crate::collector::agent::AGENT_METRICS;
+
 type Cache<K, V> = Arc<RwLock<AHashMap<K, Arc<V>>>>;
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
@@ -25,6 +29,92 @@ pub struct Workload {
     pub kind: String,
 }
 
+/// The name and `appProtocol` of a Service port, or the name of a container port.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct PortName {
+    pub name: String,
+    pub app_protocol: String,
+}
+
+/// Label of EndpointSlices naming the Service they belong to.
+const SERVICE_NAME_LABEL: &str = "kubernetes.io/service-name";
+
+/// The ready endpoints behind a port of a Service, as listed by its EndpointSlices.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ServiceBackend {
+    pub addrs: Vec<IpAddr>,
+    pub port: u16,
+}
+
+/// The spec and EndpointSlices of a Service, and the keys of the backends last derived
+/// from them.
+#[derive(Clone, Debug, Default)]
+pub struct ServiceEndpoints {
+    spec: Option<ServiceSpec>,
+    slices: AHashMap<String, EndpointSlice>,
+    backend_keys: Vec<(IpAddr, u16)>,
+}
+
+impl ServiceEndpoints {
+    /// Returns the backends of the ports of the Service, keyed by cluster IP and port.
+    fn backends(&self) -> Vec<((IpAddr, u16), ServiceBackend)> {
+        let Some(spec) = self.spec.as_ref() else {
+            return vec![];
+        };
+        // Endpoints not known to be unready are considered ready.
+        let addrs: Vec<IpAddr> = self
+            .slices
+            .values()
+            .flat_map(|slice| slice.endpoints.iter())
+            .filter(|endpoint| {
+                endpoint
+                    .conditions
+                    .as_ref()
+                    .and_then(|conditions| conditions.ready)
+                    != Some(false)
+            })
+            .flat_map(|endpoint| endpoint.addresses.iter())
+            .filter_map(|addr| addr.parse::<IpAddr>().ok())
+            .map(|addr| addr.to_canonical())
+            .collect();
+        let cluster_ips: Vec<IpAddr> = spec
+            .cluster_ips
+            .iter()
+            .flatten()
+            .filter_map(|ip| ip.parse::<IpAddr>().ok())
+            .collect();
+
+        // The EndpointSlice ports carry the target ports, under the name of the Service
+        // port.
+        let mut backends = vec![];
+        for port in spec.ports.iter().flatten() {
+            let Some(number) = tcp_port(port.port, port.protocol.as_ref()) else {
+                continue;
+            };
+            let Some(target_port) = self
+                .slices
+                .values()
+                .flat_map(|slice| slice.ports.iter().flatten())
+                .find(|slice_port| slice_port.name == port.name)
+                .and_then(|slice_port| slice_port.port)
+                .and_then(|slice_port| u16::try_from(slice_port).ok())
+            else {
+                continue;
+            };
+            for ip in &cluster_ips {
+                backends.push((
+                    (ip.to_canonical(), number),
+                    ServiceBackend {
+                        addrs: addrs.clone(),
+                        port: target_port,
+                    },
+                ));
+            }
+        }
+        backends
+    }
+}
+
 #[derive(Clone, Debug)]
 pub(crate) struct CacheManager {
     pub pods: Store<Pod>,
@@ -36,8 +126,32 @@ pub(crate) struct CacheManager {
     pub daemonsets: Store<DaemonSet>,
     pub jobs: Store<Job>,
     pub cronjobs: Store<CronJob>,
+    pub endpointslices: Store<EndpointSlice>,
     pub pod_descriptors: Cache<ObjectRef<Pod>, Workload>,
-    pub ip_to_workload: Cache<String, Workload>,
+    pub ip_to_workload: Cache<IpAddr, Workload>,
+    pub port_names: Cache<(IpAddr, u16), PortName>,
+    pub service_endpoints: Arc<RwLock<AHashMap<ObjectRef<Service>, ServiceEndpoints>>>,
+    pub service_backends: Cache<(IpAddr, u16), ServiceBackend>,
+}
+
+/// Records a failed watch. The backoff of the watcher restarts it on the next poll.
+fn record_watch_restart(kind: &str, e: &watcher::Error) {
+    warn!("Watch of {} failed and will be restarted: {}", kind, e);
+    AGENT_METRICS.record_watch_restart(kind);
+}
+
+/// Returns `port` if it is a TCP port, the only protocol traced by programs.
+fn tcp_port(port: i32, protocol: Option<&String>) -> Option<u16> {
+    match protocol.map(String::as_str) {
+        None | Some("TCP") => u16::try_from(port).ok(),
+        _ => None,
+    }
+}
+
+/// Returns the Service `endpointslice` belongs to.
+fn service_of(endpointslice: &EndpointSlice) -> Option<ObjectRef<Service>> {
+    let service = endpointslice.labels().get(SERVICE_NAME_LABEL)?;
+    Some(ObjectRef::new(service).within(&endpointslice.namespace().unwrap_or_default()))
 }
 
 macro_rules! spawn_watcher {
@@ -62,6 +176,7 @@ impl CacheManager {
         let (ds_reader, ds_writer) = reflector::store::<DaemonSet>();
         let (jobs_reader, jobs_writer) = reflector::store::<Job>();
         let (cronjobs_reader, cronjobs_writer) = reflector::store::<CronJob>();
+        let (eps_reader, eps_writer) = reflector::store::<EndpointSlice>();
 
         let cache_mgr = Self {
             pods: pod_reader,
@@ -73,8 +188,12 @@ impl CacheManager {
             daemonsets: ds_reader,
             jobs: jobs_reader,
             cronjobs: cronjobs_reader,
+            endpointslices: eps_reader,
             pod_descriptors: Arc::new(RwLock::new(AHashMap::new())),
             ip_to_workload: Arc::new(RwLock::new(AHashMap::new())),
+            port_names: Arc::new(RwLock::new(AHashMap::new())),
+            service_endpoints: Arc::new(RwLock::new(AHashMap::new())),
+            service_backends: Arc::new(RwLock::new(AHashMap::new())),
         };
 
         spawn_watcher!(cache_mgr, Pod, pod_writer, watching_pods);
@@ -86,10 +205,150 @@ impl CacheManager {
         spawn_watcher!(cache_mgr, DaemonSet, ds_writer, watching_daemonsets);
         spawn_watcher!(cache_mgr, Job, jobs_writer, watching_jobs);
         spawn_watcher!(cache_mgr, CronJob, cronjobs_writer, watching_cronjobs);
+        spawn_watcher!(
+            cache_mgr,
+            EndpointSlice,
+            eps_writer,
+            watching_endpointslices
+        );
 
         Ok(cache_mgr)
     }
 
+    /// Returns a cache manager without watchers, filled in by tests.
+    #[cfg(test)]
+    pub(crate) fn empty() -> CacheManager {
+        Self {
+            pods: reflector::store().0,
+            nodes: reflector::store().0,
+            services: reflector::store().0,
+            replicasets: reflector::store().0,
+            deployments: reflector::store().0,
+            statefulsets: reflector::store().0,
+            daemonsets: reflector::store().0,
+            jobs: reflector::store().0,
+            cronjobs: reflector::store().0,
+            endpointslices: reflector::store().0,
+            pod_descriptors: Arc::new(RwLock::new(AHashMap::new())),
+            ip_to_workload: Arc::new(RwLock::new(AHashMap::new())),
+            port_names: Arc::new(RwLock::new(AHashMap::new())),
+            service_endpoints: Arc::new(RwLock::new(AHashMap::new())),
+            service_backends: Arc::new(RwLock::new(AHashMap::new())),
+        }
+    }
+
+    /// Returns the workload owning `ip`, which may be of either family.
+    pub(crate) fn resolve_ip(&self, ip: IpAddr) -> Option<Arc<Workload>> {
+        self.ip_to_workload.read().get(&ip.to_canonical()).cloned()
+    }
+
+    /// Returns the name of TCP `port` of the Service or pod at `ip`.
+    pub(crate) fn resolve_port(&self, ip: IpAddr, port: u16) -> Option<Arc<PortName>> {
+        self.port_names
+            .read()
+            .get(&(ip.to_canonical(), port))
+            .cloned()
+    }
+
+    /// Returns the workload backing `port` of the Service at `ip`, and the port the
+    /// backends listen on. Services whose ready endpoints belong to several workloads
+    /// have no single backend, and `None` is returned.
+    pub(crate) fn resolve_backend(&self, ip: IpAddr, port: u16) -> Option<(Arc<Workload>, u16)> {
+        let backend = self
+            .service_backends
+            .read()
+            .get(&(ip.to_canonical(), port))
+            .cloned()?;
+        let ips = self.ip_to_workload.read();
+        let mut workloads = backend.addrs.iter().filter_map(|addr| ips.get(addr));
+        let workload = workloads.next()?.clone();
+        workloads
+            .all(|other| *other == workload)
+            .then_some((workload, backend.port))
+    }
+
+    /// Rebuilds the backends of the ports of `service` from its spec and EndpointSlices.
+    fn update_service_backends(&self, service: &ObjectRef<Service>) {
+        let mut index = self.service_endpoints.write();
+        let Some(endpoints) = index.get_mut(service) else {
+            return;
+        };
+        let backends = endpoints.backends();
+        let mut service_backends = self.service_backends.write();
+        for key in endpoints.backend_keys.drain(..) {
+            service_backends.remove(&key);
+        }
+        for (key, backend) in backends {
+            endpoints.backend_keys.push(key);
+            service_backends.insert(key, Arc::new(backend));
+        }
+        if endpoints.spec.is_none() && endpoints.slices.is_empty() {
+            index.remove(service);
+        }
+    }
+
+    fn apply_service(&self, service: &Service) {
+        let service_ref = ObjectRef::from_obj(service);
+        self.service_endpoints
+            .write()
+            .entry(service_ref.clone())
+            .or_default()
+            .spec = service.spec.clone();
+        self.update_service_backends(&service_ref);
+    }
+
+    fn apply_endpointslice(&self, endpointslice: EndpointSlice) {
+        let Some(service) = service_of(&endpointslice) else {
+            return;
+        };
+        self.service_endpoints
+            .write()
+            .entry(service.clone())
+            .or_default()
+            .slices
+            .insert(endpointslice.name_any(), endpointslice);
+        self.update_service_backends(&service);
+    }
+
+    fn remove_endpointslice(&self, service: &ObjectRef<Service>, name: &str) {
+        if let Some(endpoints) = self.service_endpoints.write().get_mut(service) {
+            endpoints.slices.remove(name);
+        }
+        self.update_service_backends(service);
+    }
+
+    /// Removes the EndpointSlices that were not listed again after a restart of the
+    /// watch, as their deletion was missed.
+    fn prune_endpointslices(&self, listed: &AHashSet<(Option<String>, String)>) {
+        let stale: Vec<(ObjectRef<Service>, String)> = self
+            .service_endpoints
+            .read()
+            .iter()
+            .flat_map(|(service, endpoints)| {
+                endpoints
+                    .slices
+                    .keys()
+                    .filter(|name| !listed.contains(&(service.namespace.clone(), (*name).clone())))
+                    .map(|name| (service.clone(), name.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        for (service, name) in stale {
+            self.remove_endpointslice(&service, &name);
+        }
+    }
+
+    /// Records `ports` as the named ports of each of `ips`.
+    fn insert_port_names(&self, ips: &[IpAddr], ports: Vec<(u16, PortName)>) {
+        let mut port_names = self.port_names.write();
+        for (port, port_name) in ports {
+            let port_name = Arc::new(port_name);
+            for ip in ips {
+                port_names.insert((ip.to_canonical(), port), port_name.clone());
+            }
+        }
+    }
+
     async fn get_controller_of_owner(
         &self,
         owner_ref: OwnerReference,
@@ -232,8 +491,21 @@ impl CacheManager {
         let api: Api<Pod> = Api::all(client);
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
+            .inspect_err(|e| record_watch_restart("Pod", e))
             .modify(|pod| {
-                pod.spec = None;
+                // Only the container ports are kept, to name the ports of pods.
+                pod.spec = pod.spec.take().map(|spec| PodSpec {
+                    containers: spec
+                        .containers
+                        .into_iter()
+                        .map(|container| Container {
+                            name: container.name,
+                            ports: container.ports,
+                            ..Default::default()
+                        })
+                        .collect(),
+                    ..Default::default()
+                });
                 pod.managed_fields_mut().clear();
                 pod.annotations_mut().clear();
             })
@@ -242,16 +514,24 @@ impl CacheManager {
             .predicate_filter(predicates::resource_version);
         futures::pin_mut!(stream);
 
-        while let Some(pod) = stream.try_next().await? {
+        while let Some(pod) = stream.next().await {
+            let Ok(pod) = pod else {
+                continue;
+            };
             let entry = self.resolve_pod_descriptor(&pod).await;
+            let mut pod_addrs = vec![];
             let mut ips = self.ip_to_workload.write();
             if let Some(status) = pod.status.as_ref() {
                 if let Some(pod_ips) = status.pod_ips.as_ref() {
                     for ip in pod_ips {
                         match ip.ip.clone() {
-                            Some(ip) => {
-                                ips.insert(ip, entry.clone());
-                            }
+                            Some(ip) => match ip.parse::<IpAddr>() {
+                                Ok(ip) => {
+                                    ips.insert(ip.to_canonical(), entry.clone());
+                                    pod_addrs.push(ip);
+                                }
+                                Err(e) => debug!("Failed to parse IP: {:?}, skipping", e),
+                            },
                             None => {
                                 debug!("IP is None, skipping");
                                 continue;
@@ -260,6 +540,26 @@ impl CacheManager {
                     }
                 }
             }
+            drop(ips);
+
+            let ports = pod
+                .spec
+                .iter()
+                .flat_map(|spec| spec.containers.iter())
+                .flat_map(|container| container.ports.iter().flatten())
+                .filter_map(|port| {
+                    let name = port.name.clone()?;
+                    let number = tcp_port(port.container_port, port.protocol.as_ref())?;
+                    Some((
+                        number,
+                        PortName {
+                            name,
+                            app_protocol: String::new(),
+                        },
+                    ))
+                })
+                .collect();
+            self.insert_port_names(&pod_addrs, ports);
         }
 
         Ok(())
@@ -271,6 +571,7 @@ impl CacheManager {
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
+            .inspect_err(|e| record_watch_restart("Node", e))
             .modify(|node| {
                 node.spec = None;
                 node.metadata.managed_fields = None;
@@ -281,13 +582,20 @@ impl CacheManager {
             .predicate_filter(predicates::resource_version);
         futures::pin_mut!(stream);
 
-        while let Some(node) = stream.try_next().await? {
+        while let Some(node) = stream.next().await {
+            let Ok(node) = node else {
+                continue;
+            };
             let mut ips = self.ip_to_workload.write();
             if let Some(status) = node.status.as_ref() {
                 if let Some(addresses) = status.addresses.as_ref() {
+                    // Hostname addresses are not IPs and are skipped.
                     for addr in addresses {
+                        let Ok(ip) = addr.address.parse::<IpAddr>() else {
+                            continue;
+                        };
                         ips.insert(
-                            addr.address.clone(),
+                            ip.to_canonical(),
                             Arc::new(Workload {
                                 name: node.name_any(),
                                 namespace: "node".to_string(),
@@ -308,6 +616,7 @@ impl CacheManager {
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
+            .inspect_err(|e| record_watch_restart("Service", e))
             .modify(|service| {
                 service.metadata.managed_fields = None;
                 service.metadata.annotations = None;
@@ -317,18 +626,21 @@ impl CacheManager {
             .predicate_filter(predicates::resource_version);
         futures::pin_mut!(stream);
 
-        while let Some(service) = stream.try_next().await? {
+        while let Some(service) = stream.next().await {
+            let Ok(service) = service else {
+                continue;
+            };
+            let mut service_addrs = vec![];
             let mut ips = self.ip_to_workload.write();
             if let Some(spec) = service.spec.as_ref() {
                 if let Some(cluster_ips) = spec.cluster_ips.as_ref() {
                     for ip_str in cluster_ips {
-                        match ip_str.clone().parse() {
+                        // Headless services have a cluster IP of "None", which fails to parse.
+                        match ip_str.parse::<IpAddr>() {
                             Ok(ip) => {
-                                if ip == "None" {
-                                    continue;
-                                }
+                                service_addrs.push(ip);
                                 ips.insert(
-                                    ip,
+                                    ip.to_canonical(),
                                     Arc::new(Workload {
                                         name: service.name_any(),
                                         namespace: service.namespace().unwrap_or_default(),
@@ -344,6 +656,27 @@ impl CacheManager {
                     }
                 }
             }
+            drop(ips);
+
+            let ports = service
+                .spec
+                .iter()
+                .flat_map(|spec| spec.ports.iter().flatten())
+                // Single port Services may leave their port unnamed, yet set `appProtocol`.
+                .filter(|port| port.name.is_some() || port.app_protocol.is_some())
+                .filter_map(|port| {
+                    let number = tcp_port(port.port, port.protocol.as_ref())?;
+                    Some((
+                        number,
+                        PortName {
+                            name: port.name.clone().unwrap_or_default(),
+                            app_protocol: port.app_protocol.clone().unwrap_or_default(),
+                        },
+                    ))
+                })
+                .collect();
+            self.insert_port_names(&service_addrs, ports);
+            self.apply_service(&service);
         }
 
         Ok(())
@@ -355,6 +688,7 @@ impl CacheManager {
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
+            .inspect_err(|e| record_watch_restart("ReplicaSet", e))
             .modify(|replicaset| {
                 replicaset.spec = None;
                 replicaset.metadata.managed_fields = None;
@@ -376,6 +710,7 @@ impl CacheManager {
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
+            .inspect_err(|e| record_watch_restart("Deployment", e))
             .modify(|deployment| {
                 deployment.spec = None;
                 deployment.metadata.managed_fields = None;
@@ -395,6 +730,7 @@ impl CacheManager {
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
+            .inspect_err(|e| record_watch_restart("DaemonSet", e))
             .modify(|daemonset| {
                 daemonset.spec = None;
                 daemonset.metadata.managed_fields = None;
@@ -415,6 +751,7 @@ impl CacheManager {
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
+            .inspect_err(|e| record_watch_restart("StatefulSet", e))
             .modify(|statefulset| {
                 statefulset.spec = None;
                 statefulset.metadata.managed_fields = None;
@@ -434,6 +771,7 @@ impl CacheManager {
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
+            .inspect_err(|e| record_watch_restart("Job", e))
             .modify(|job| {
                 job.spec = None;
                 job.metadata.managed_fields = None;
@@ -453,6 +791,7 @@ impl CacheManager {
 
         let stream = watcher(api, watcher::Config::default().any_semantic())
             .default_backoff()
+            .inspect_err(|e| record_watch_restart("CronJob", e))
             .modify(|cronjob| {
                 cronjob.spec = None;
                 cronjob.metadata.managed_fields = None;
@@ -466,6 +805,51 @@ impl CacheManager {
         Ok(())
     }
 
+    async fn watching_endpointslices(&self, writer: Writer<EndpointSlice>) -> anyhow::Result<()> {
+        let client = Client::try_default().await?;
+        let api: Api<EndpointSlice> = Api::all(client);
+
+        // Slices are indexed by Service from the watch events, so that deleted slices
+        // stop being backends.
+        let stream = watcher(api, watcher::Config::default().any_semantic())
+            .default_backoff()
+            .inspect_err(|e| record_watch_restart("EndpointSlice", e))
+            .modify(|endpointslice| {
+                endpointslice.metadata.managed_fields = None;
+                endpointslice.metadata.annotations = None;
+            })
+            .reflect(writer);
+        futures::pin_mut!(stream);
+
+        while let Some(event) = stream.next().await {
+            let Ok(event) = event else {
+                continue;
+            };
+            match event {
+                watcher::Event::Applied(endpointslice) => self.apply_endpointslice(endpointslice),
+                watcher::Event::Deleted(endpointslice) => {
+                    if let Some(service) = service_of(&endpointslice) {
+                        self.remove_endpointslice(&service, &endpointslice.name_any());
+                    }
+                }
+                // A relist replaces all slices, so slices deleted while the watch was
+                // down are pruned.
+                watcher::Event::Restarted(endpointslices) => {
+                    let listed = endpointslices
+                        .iter()
+                        .map(|endpointslice| (endpointslice.namespace(), endpointslice.name_any()))
+                        .collect();
+                    for endpointslice in endpointslices {
+                        self.apply_endpointslice(endpointslice);
+                    }
+                    self.prune_endpointslices(&listed);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
     pub async fn wait_for_cache_sync(&self) -> anyhow::Result<()> {
         let pods = self.pods.clone();
         pods.wait_until_ready().await?;
@@ -485,8 +869,119 @@ impl CacheManager {
         jobs.wait_until_ready().await?;
         let cronjobs = self.cronjobs.clone();
         cronjobs.wait_until_ready().await?;
+        let endpointslices = self.endpointslices.clone();
+        endpointslices.wait_until_ready().await?;
 
         info!("Cache sync complete");
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::ServicePort;
+    use k8s_openapi::api::discovery::v1::{Endpoint, EndpointConditions, EndpointPort};
+    use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+
+    use super::*;
+
+    fn workload(name: &str) -> Arc<Workload> {
+        Arc::new(Workload {
+            name: name.to_string(),
+            namespace: "default".to_string(),
+            kind: "Deployment".to_string(),
+        })
+    }
+
+    fn service(name: &str, cluster_ip: &str) -> Service {
+        Service {
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                namespace: Some("default".to_string()),
+                ..Default::default()
+            },
+            spec: Some(ServiceSpec {
+                cluster_ips: Some(vec![cluster_ip.to_string()]),
+                ports: Some(vec![ServicePort {
+                    name: Some("http".to_string()),
+                    port: 80,
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    fn endpointslice(service: &str, name: &str, endpoints: &[(&str, bool)]) -> EndpointSlice {
+        EndpointSlice {
+            address_type: "IPv4".to_string(),
+            metadata: ObjectMeta {
+                name: Some(name.to_string()),
+                namespace: Some("default".to_string()),
+                labels: Some([(SERVICE_NAME_LABEL.to_string(), service.to_string())].into()),
+                ..Default::default()
+            },
+            endpoints: endpoints
+                .iter()
+                .map(|(addr, ready)| Endpoint {
+                    addresses: vec![addr.to_string()],
+                    conditions: Some(EndpointConditions {
+                        ready: Some(*ready),
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                })
+                .collect(),
+            ports: Some(vec![EndpointPort {
+                name: Some("http".to_string()),
+                port: Some(8080),
+                ..Default::default()
+            }]),
+        }
+    }
+
+    #[test]
+    fn test_resolve_backend() {
+        let cache_mgr = CacheManager::empty();
+        {
+            let mut ips = cache_mgr.ip_to_workload.write();
+            ips.insert("10.0.0.1".parse().unwrap(), workload("api"));
+            ips.insert("10.0.0.2".parse().unwrap(), workload("api"));
+            ips.insert("10.0.0.3".parse().unwrap(), workload("api-canary"));
+        }
+        let vip: IpAddr = "10.96.0.10".parse().unwrap();
+
+        // Slices seen before their Service are kept until it is.
+        cache_mgr.apply_endpointslice(endpointslice(
+            "api",
+            "api-1",
+            &[("10.0.0.1", true), ("10.0.0.3", false)],
+        ));
+        assert_eq!(cache_mgr.resolve_backend(vip, 80), None);
+        cache_mgr.apply_service(&service("api", "10.96.0.10"));
+        assert_eq!(
+            cache_mgr.resolve_backend(vip, 80),
+            Some((workload("api"), 8080))
+        );
+        assert_eq!(cache_mgr.resolve_backend(vip, 443), None);
+
+        // A Service spread over several workloads has no single backend.
+        cache_mgr.apply_endpointslice(endpointslice(
+            "api",
+            "api-2",
+            &[("10.0.0.2", true), ("10.0.0.3", true)],
+        ));
+        assert_eq!(cache_mgr.resolve_backend(vip, 80), None);
+
+        // Slices missing from a new list are removed.
+        cache_mgr.prune_endpointslices(&AHashSet::from([(
+            Some("default".to_string()),
+            "api-1".to_string(),
+        )]));
+        assert_eq!(
+            cache_mgr.resolve_backend(vip, 80),
+            Some((workload("api"), 8080))
+        );
+    }
+}
@@ -0,0 +1,480 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use ahash::{AHashMap, AHashSet};
+use clap::ValueEnum;
+use parking_lot::Mutex;
+
+use crate::exporter::parse_key_values;
+use crate::managers::metrics::{HistogramValue, MetricFamily, MetricKind, Sample};
+use crate::managers::registry::RegistryManager;
+use crate::Args;
+
+/// Label value of the series that overflowing series are aggregated into.
+const OTHER: &str = "other";
+
+type Labels = Vec<(String, String)>;
+
+/// A series summed from the series of several label sets, such as the `other` series or
+/// a series left by dropping labels. The last values of members that left the series or
+/// were reset are kept, so that summed counters and histograms never decrease.
+#[derive(Debug, Default)]
+struct MergedSeries {
+    members: AHashMap<Labels, Sample>,
+    retired: f64,
+    retired_histogram: Option<HistogramValue>,
+    created: Option<SystemTime>,
+    /// Whether the series was reported by the current poll.
+    seen: bool,
+}
+
+impl MergedSeries {
+    fn merge(&mut self, labels: Labels, members: Vec<Sample>, kind: MetricKind) -> Sample {
+        let members: AHashMap<Labels, Sample> = members
+            .into_iter()
+            .map(|sample| (sample.labels.clone(), sample))
+            .collect();
+        if kind != MetricKind::Gauge {
+            for (member, last) in self.members.iter() {
+                let kept = members
+                    .get(member)
+                    .is_some_and(|sample| sample.value >= last.value);
+                if !kept {
+                    self.retired += last.value;
+                    add_histogram(&mut self.retired_histogram, last.histogram.as_ref());
+                }
+            }
+        }
+
+        let mut histogram = self.retired_histogram.clone();
+        for sample in members.values() {
+            add_histogram(&mut histogram, sample.histogram.as_ref());
+        }
+        self.created = members
+            .values()
+            .filter_map(|sample| sample.created)
+            .chain(self.created)
+            .min();
+        let sample = Sample {
+            labels,
+            value: self.retired + members.values().map(|sample| sample.value).sum::<f64>(),
+            created: self.created,
+            histogram,
+        };
+        self.members = members;
+        self.seen = true;
+        sample
+    }
+}
+
+/// Merged series by family name and labels.
+type MergedFamilies = AHashMap<(String, Labels), MergedSeries>;
+
+/// What the budget of a program keeps between polls.
+#[derive(Debug, Default)]
+struct ProgramState {
+    /// The label sets kept so far.
+    admitted: AHashSet<Labels>,
+    merged: MergedFamilies,
+}
+
+/// What happens to the series of a program beyond its cardinality budget. Budgets count
+/// label sets, such as the edges of the service map, so a label set is kept or removed
+/// in every metric family at once. Once kept, a label set stays kept for as long as the
+/// program reports it, and new label sets with the highest values are kept first.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum CardinalityStrategy {
+    /// Drops the label sets beyond the budget.
+    #[default]
+    Drop,
+    /// Sums the label sets beyond the budget into one label set, whose labels other
+    /// than `program` are all set to `other`. It takes up one label set of the budget.
+    Aggregate,
+    /// Always removes the configured labels and sums the series that become identical,
+    /// then drops the label sets still beyond the budget.
+    DropLabels,
+}
+
+/// Agent-wide and per-program budgets of label sets of program metrics.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct CardinalityLimits {
+    pub max_series: Option<usize>,
+    pub program_max_series: HashMap<String, usize>,
+    pub strategy: CardinalityStrategy,
+    pub drop_labels: Vec<String>,
+    state: Arc<Mutex<AHashMap<String, ProgramState>>>,
+}
+
+impl CardinalityLimits {
+    pub(crate) fn from_args(args: &Args) -> anyhow::Result<Self> {
+        let mut program_max_series = HashMap::new();
+        for (program, limit) in parse_key_values(&args.program_max_series)? {
+            program_max_series.insert(program, limit.parse()?);
+        }
+        Ok(Self {
+            max_series: (args.max_series > 0).then_some(args.max_series),
+            program_max_series,
+            strategy: args.cardinality_strategy,
+            drop_labels: args.cardinality_drop_labels.clone(),
+            state: Arc::default(),
+        })
+    }
+
+    /// Checks that the per-program budgets are given for registered programs, as a
+    /// misspelled name would silently leave a program without a budget.
+    pub(crate) fn check_programs(&self, registry: &RegistryManager) -> anyhow::Result<()> {
+        for program in self.program_max_series.keys() {
+            if registry.get_program(program, None).is_none() {
+                return Err(anyhow::anyhow!(
+                    "--program-max-series: {:?} is not a registered program",
+                    program
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns how many label sets `program` may have, given that the other programs
+    /// hold `other_series` label sets.
+    fn budget(&self, program: &str, other_series: usize) -> Option<usize> {
+        let agent_budget = self
+            .max_series
+            .map(|max_series| max_series.saturating_sub(other_series));
+        match (agent_budget, self.program_max_series.get(program)) {
+            (Some(agent_budget), Some(program_budget)) => Some(agent_budget.min(*program_budget)),
+            (agent_budget, program_budget) => agent_budget.or(program_budget.copied()),
+        }
+    }
+
+    /// Enforces the budget of `program` on its metric families, and returns the number
+    /// of series removed.
+    pub(crate) fn apply(
+        &self,
+        program: &str,
+        families: &mut [MetricFamily],
+        other_series: usize,
+    ) -> usize {
+        let mut state = self.state.lock();
+        let state = state.entry(program.to_string()).or_default();
+        for merged in state.merged.values_mut() {
+            merged.seen = false;
+        }
+        let removed = self.enforce(program, families, other_series, state);
+        state.merged.retain(|_, merged| merged.seen);
+        removed
+    }
+
+    fn enforce(
+        &self,
+        program: &str,
+        families: &mut [MetricFamily],
+        other_series: usize,
+        state: &mut ProgramState,
+    ) -> usize {
+        if self.strategy == CardinalityStrategy::DropLabels && !self.drop_labels.is_empty() {
+            for family in families.iter_mut() {
+                drop_labels(family, &self.drop_labels, &mut state.merged);
+            }
+        }
+        let Some(budget) = self.budget(program, other_series) else {
+            return 0;
+        };
+
+        // The highest value of each label set across families ranks new label sets.
+        let mut values: AHashMap<&Labels, f64> = AHashMap::new();
+        for sample in families.iter().flat_map(|family| family.samples.iter()) {
+            let value = values.entry(&sample.labels).or_insert(sample.value);
+            *value = value.max(sample.value);
+        }
+        let aggregate = self.strategy == CardinalityStrategy::Aggregate;
+        let capacity = if aggregate {
+            budget.saturating_sub(1)
+        } else {
+            budget
+        };
+
+        let admitted = &mut state.admitted;
+        // Label sets no longer reported free their place in the budget.
+        admitted.retain(|labels| values.contains_key(labels));
+        let mut candidates: Vec<(&Labels, f64)> = values
+            .iter()
+            .filter(|(labels, _)| !admitted.contains(**labels))
+            .map(|(labels, value)| (*labels, *value))
+            .collect();
+        candidates.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(b.0)));
+        let room = capacity.saturating_sub(admitted.len());
+        let new: Vec<Labels> = candidates
+            .into_iter()
+            .take(room)
+            .map(|(labels, _)| labels.clone())
+            .collect();
+        admitted.extend(new);
+
+        let mut removed = 0;
+        for family in families.iter_mut() {
+            let (kept, overflow): (Vec<Sample>, Vec<Sample>) = family
+                .samples
+                .drain(..)
+                .partition(|sample| admitted.contains(&sample.labels));
+            family.samples = kept;
+            removed += overflow.len();
+            if aggregate && budget > 0 && !overflow.is_empty() {
+                let sample = other_sample(&family.name, family.kind, overflow, &mut state.merged);
+                family.samples.push(sample);
+            }
+        }
+        removed
+    }
+
+    /// Forgets the label sets kept and the series merged for `program`, once it is
+    /// removed.
+    pub(crate) fn forget(&self, program: &str) {
+        self.state.lock().remove(program);
+    }
+}
+
+/// Returns the number of distinct label sets of `families`, which budgets count.
+pub(crate) fn label_sets(families: &[MetricFamily]) -> usize {
+    families
+        .iter()
+        .flat_map(|family| family.samples.iter())
+        .map(|sample| &sample.labels)
+        .collect::<AHashSet<_>>()
+        .len()
+}
+
+/// Removes `labels` from the samples of `family`, summing samples that end up with the
+/// same labels.
+fn drop_labels(family: &mut MetricFamily, labels: &[String], merged: &mut MergedFamilies) {
+    let mut groups: Vec<(Labels, Vec<Sample>)> = vec![];
+    let mut index: AHashMap<Labels, usize> = AHashMap::new();
+    for sample in family.samples.drain(..) {
+        let mut kept = sample.labels.clone();
+        kept.retain(|(key, _)| !labels.contains(key));
+        match index.get(&kept) {
+            Some(i) => groups[*i].1.push(sample),
+            None => {
+                index.insert(kept.clone(), groups.len());
+                groups.push((kept, vec![sample]));
+            }
+        }
+    }
+    family.samples = groups
+        .into_iter()
+        .map(|(labels, members)| {
+            merged
+                .entry((family.name.clone(), labels.clone()))
+                .or_default()
+                .merge(labels, members, family.kind)
+        })
+        .collect();
+}
+
+fn other_sample(
+    family: &str,
+    kind: MetricKind,
+    overflow: Vec<Sample>,
+    merged: &mut MergedFamilies,
+) -> Sample {
+    let labels: Labels = overflow
+        .first()
+        .map(|sample| {
+            sample
+                .labels
+                .iter()
+                .map(|(key, value)| match key.as_str() {
+                    "program" => (key.clone(), value.clone()),
+                    _ => (key.clone(), OTHER.to_string()),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    merged
+        .entry((family.to_string(), labels.clone()))
+        .or_default()
+        .merge(labels, overflow, kind)
+}
+
+fn add_histogram(sum: &mut Option<HistogramValue>, histogram: Option<&HistogramValue>) {
+    match (sum.as_mut(), histogram) {
+        (Some(sum), Some(histogram)) => sum.merge(histogram),
+        (None, Some(histogram)) => *sum = Some(histogram.clone()),
+        (_, None) => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::managers::metrics::MetricKind;
+
+    fn family(name: &str, values: &[(&str, f64)]) -> MetricFamily {
+        let mut family = MetricFamily::new(name, "bytes of connections", None, MetricKind::Gauge);
+        for (port, value) in values {
+            family.samples.push(Sample::new(
+                [
+                    ("program", "service_map".to_string()),
+                    ("server_port", port.to_string()),
+                ],
+                *value,
+            ));
+        }
+        family
+    }
+
+    fn connections() -> Vec<MetricFamily> {
+        vec![family(
+            "connection_observed",
+            &[("80", 10.0), ("8080", 30.0), ("30001", 1.0), ("30002", 2.0)],
+        )]
+    }
+
+    fn ports(family: &MetricFamily) -> Vec<&str> {
+        family
+            .samples
+            .iter()
+            .map(|sample| sample.labels[1].1.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn test_cardinality_strategies() {
+        let mut limits = CardinalityLimits {
+            max_series: Some(10),
+            program_max_series: HashMap::from([("service_map".to_string(), 3)]),
+            strategy: CardinalityStrategy::Aggregate,
+            drop_labels: vec!["server_port".to_string()],
+            ..Default::default()
+        };
+
+        let mut families = connections();
+        assert_eq!(limits.apply("service_map", &mut families, 8), 3);
+        assert_eq!(
+            families[0].samples,
+            vec![
+                Sample::new(
+                    [
+                        ("program", "service_map".to_string()),
+                        ("server_port", "8080".to_string())
+                    ],
+                    30.0
+                ),
+                Sample::new(
+                    [
+                        ("program", "service_map".to_string()),
+                        ("server_port", OTHER.to_string())
+                    ],
+                    13.0
+                ),
+            ]
+        );
+
+        // Labels are dropped even when the budget is not exceeded.
+        limits.strategy = CardinalityStrategy::DropLabels;
+        limits.forget("service_map");
+        let mut families = connections();
+        assert_eq!(limits.apply("service_map", &mut families, 0), 0);
+        assert_eq!(
+            families[0].samples,
+            vec![Sample::new([("program", "service_map".to_string())], 43.0)]
+        );
+    }
+
+    #[test]
+    fn test_cardinality_budget_across_families() {
+        let limits = CardinalityLimits {
+            program_max_series: HashMap::from([("service_map".to_string(), 2)]),
+            ..Default::default()
+        };
+
+        let mut families = vec![
+            family(
+                "connection_sent",
+                &[("80", 10.0), ("8080", 30.0), ("443", 1.0)],
+            ),
+            family(
+                "connection_received",
+                &[("80", 5.0), ("8080", 1.0), ("443", 50.0)],
+            ),
+        ];
+        assert_eq!(limits.apply("service_map", &mut families, 0), 2);
+        assert_eq!(ports(&families[0]), vec!["8080", "443"]);
+        assert_eq!(ports(&families[1]), vec!["8080", "443"]);
+
+        // Kept label sets stay kept, even once others have higher values.
+        let mut families = vec![
+            family(
+                "connection_sent",
+                &[("80", 100.0), ("8080", 30.0), ("443", 1.0)],
+            ),
+            family(
+                "connection_received",
+                &[("80", 100.0), ("8080", 1.0), ("443", 50.0)],
+            ),
+        ];
+        assert_eq!(limits.apply("service_map", &mut families, 0), 2);
+        assert_eq!(ports(&families[0]), vec!["8080", "443"]);
+
+        // Label sets no longer reported make room for new ones.
+        let mut families = vec![family("connection_sent", &[("80", 100.0), ("8080", 30.0)])];
+        assert_eq!(limits.apply("service_map", &mut families, 0), 0);
+        assert_eq!(ports(&families[0]), vec!["80", "8080"]);
+    }
+
+    #[test]
+    fn test_check_programs() {
+        let registry = RegistryManager::new();
+        let limits = |program: &str| CardinalityLimits {
+            program_max_series: HashMap::from([(program.to_string(), 10)]),
+            ..Default::default()
+        };
+        assert!(limits("service_map").check_programs(&registry).is_ok());
+        assert!(limits("service-map").check_programs(&registry).is_err());
+    }
+
+    #[test]
+    fn test_merged_counters_never_decrease() {
+        let counter = |values: &[(&str, f64)]| {
+            let mut family = family("connection_sent", values);
+            family.kind = MetricKind::Counter;
+            vec![family]
+        };
+        let other = |families: &[MetricFamily]| {
+            families[0]
+                .samples
+                .iter()
+                .find(|sample| sample.labels[1].1 == OTHER)
+                .map(|sample| sample.value)
+        };
+        let mut limits = CardinalityLimits {
+            program_max_series: HashMap::from([("service_map".to_string(), 2)]),
+            strategy: CardinalityStrategy::Aggregate,
+            ..Default::default()
+        };
+
+        let mut families = counter(&[("8080", 30.0), ("80", 10.0), ("443", 5.0), ("22", 1.0)]);
+        limits.apply("service_map", &mut families, 0);
+        assert_eq!(other(&families), Some(16.0));
+        // A member of `other` expires.
+        let mut families = counter(&[("8080", 30.0), ("80", 12.0), ("22", 1.0)]);
+        limits.apply("service_map", &mut families, 0);
+        assert_eq!(other(&families), Some(18.0));
+        // A member of `other` is kept once a kept label set expires.
+        let mut families = counter(&[("80", 12.0), ("22", 2.0)]);
+        limits.apply("service_map", &mut families, 0);
+        assert_eq!(ports(&families[0]), vec!["80", OTHER]);
+        assert_eq!(other(&families), Some(19.0));
+
+        limits.strategy = CardinalityStrategy::DropLabels;
+        limits.drop_labels = vec!["server_port".to_string()];
+        limits.program_max_series.clear();
+        limits.forget("service_map");
+        let mut families = counter(&[("80", 10.0), ("8080", 30.0)]);
+        limits.apply("service_map", &mut families, 0);
+        assert_eq!(families[0].samples[0].value, 40.0);
+        let mut families = counter(&[("80", 11.0)]);
+        limits.apply("service_map", &mut families, 0);
+        assert_eq!(families[0].samples[0].value, 41.0);
+    }
+}
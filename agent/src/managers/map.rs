@@ -0,0 +1,253 @@
+use std::hash::Hasher;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ahash::{AHashMap, AHashSet};
+use bpfman_lib::directories::RTDIR_FS_MAPS;
+use fnv::FnvHasher;
+use k8s_openapi::api::core::v1::ObjectReference;
+use kube::runtime::events::{Event, EventType, Recorder, Reporter};
+use kube::Client;
+use log::{debug, info, warn};
+use parking_lot::RwLock;
+use tokio::sync::broadcast::Receiver;
+use tokio::time;
+
+use agent_api::ProgramState;
+
+use crate::common::bpf::pinned_map_keys;
+use crate::common::constants::{DEFAULT_INTERVAL, MAP_PRESSURE_THRESHOLD, NODE_NAME_ENV};
+use crate::common::types::ListFilter;
+use crate::managers::registry::RegistryManager;
+use crate::progs::types::ShutdownSignal;
+
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MapStats {
+    pub entries: u64,
+    pub max_entries: u64,
+    /// Estimated evictions per second since the previous sample.
+    pub eviction_rate: f64,
+}
+
+#[derive(Debug)]
+struct MapState {
+    keys: AHashSet<u64>,
+    stats: MapStats,
+    sampled_at: Instant,
+    under_pressure: bool,
+}
+
+/// Keeps track of the fill level of the eBPF maps bound to running programs.
+#[derive(Clone, Debug)]
+pub(crate) struct MapManager {
+    registry_manager: RegistryManager,
+    maps: Arc<RwLock<AHashMap<(String, String), MapState>>>,
+}
+
+fn key_hash(key: &[u8]) -> u64 {
+    let mut hasher = FnvHasher::default();
+    hasher.write(key);
+    hasher.finish()
+}
+
+/// Estimates how many of `previous` keys were evicted to get to `current`. The kernel
+/// does not report evictions, and a full map only evicts to make room for new keys, so
+/// evictions are the vanished keys up to the number of new keys that did not fit in the
+/// room left by `previous`. Keys the program deleted while the map had room are not
+/// counted.
+fn evicted_keys(previous: &AHashSet<u64>, current: &AHashSet<u64>, max_entries: usize) -> usize {
+    let appeared = current.difference(previous).count();
+    let vanished = previous.difference(current).count();
+    let room = max_entries.saturating_sub(previous.len());
+    vanished.min(appeared.saturating_sub(room))
+}
+
+impl MapManager {
+    pub(crate) fn new(registry_manager: RegistryManager) -> Self {
+        Self {
+            registry_manager,
+            maps: Arc::new(RwLock::new(AHashMap::new())),
+        }
+    }
+
+    /// Returns the latest stats of every bound map, keyed by program and map name.
+    pub(crate) fn stats(&self) -> Vec<(String, String, MapStats)> {
+        let maps = self.maps.read();
+        maps.iter()
+            .map(|((prog, map), state)| (prog.clone(), map.clone(), state.stats.clone()))
+            .collect()
+    }
+
+    pub(crate) async fn start(&self, mut shutdown_rx: Receiver<ShutdownSignal>) {
+        let mut interval = time::interval(Duration::from_secs(DEFAULT_INTERVAL));
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {
+                    self.sample().await;
+                }
+                Ok(signal) = shutdown_rx.recv() => {
+                    if let ShutdownSignal::All = signal {
+                        info!("Received shutdown signal, stopping map manager.");
+                        break;
+                    }
+                },
+            }
+        }
+    }
+
+    async fn sample(&self) {
+        let mut bound = Vec::new();
+        for prog in self.registry_manager.list_programs(ListFilter::default()) {
+            if prog.get_state() != ProgramState::Running {
+                continue;
+            }
+            let info = match prog.get_program_info() {
+                Ok(info) => info,
+                Err(e) => {
+                    debug!("Failed to get program info of {}: {:?}", prog.get_name(), e);
+                    continue;
+                }
+            };
+            for (map_name, prog_id) in info.ebpf_maps {
+                bound.push((info.name.clone(), map_name, prog_id));
+            }
+        }
+
+        // Walking the keys makes a syscall per key, so it runs off the runtime and
+        // without holding the lock `stats` needs.
+        let walk = bound.clone();
+        let sampled = match tokio::task::spawn_blocking(move || {
+            walk.into_iter()
+                .map(|(prog_name, map_name, prog_id)| {
+                    let map_pin_path =
+                        Path::new(RTDIR_FS_MAPS).join(format!("{}/{}", prog_id, map_name));
+                    let res = pinned_map_keys(&map_pin_path)
+                        .map(|(info, keys)| {
+                            let keys: AHashSet<u64> = keys.iter().map(|k| key_hash(k)).collect();
+                            (info, keys)
+                        })
+                        .map_err(|e| {
+                            debug!("Failed to sample map {}: {:?}", map_pin_path.display(), e)
+                        });
+                    (prog_name, map_name, res, Instant::now())
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        {
+            Ok(sampled) => sampled,
+            Err(e) => {
+                debug!("Failed to sample maps: {:?}", e);
+                return;
+            }
+        };
+
+        let mut warnings = Vec::new();
+        {
+            let mut maps = self.maps.write();
+            maps.retain(|(prog, map), _| bound.iter().any(|(p, m, _)| p == prog && m == map));
+
+            for (prog_name, map_name, res, now) in sampled {
+                let Ok((info, keys)) = res else {
+                    continue;
+                };
+                let max_entries = info.max_entries as usize;
+                let fill = keys.len() as f64 / max_entries.max(1) as f64;
+
+                let state = maps
+                    .entry((prog_name.clone(), map_name.clone()))
+                    .or_insert_with(|| MapState {
+                        keys: AHashSet::new(),
+                        stats: MapStats::default(),
+                        sampled_at: now,
+                        under_pressure: false,
+                    });
+
+                let evicted = evicted_keys(&state.keys, &keys, max_entries);
+                let elapsed = now.duration_since(state.sampled_at).as_secs_f64();
+                let eviction_rate = if elapsed > 0.0 {
+                    evicted as f64 / elapsed
+                } else {
+                    0.0
+                };
+
+                state.stats = MapStats {
+                    entries: keys.len() as u64,
+                    max_entries: info.max_entries as u64,
+                    eviction_rate,
+                };
+                state.keys = keys;
+                state.sampled_at = now;
+
+                if fill >= MAP_PRESSURE_THRESHOLD && !state.under_pressure {
+                    warnings.push((prog_name, map_name, state.stats.clone()));
+                }
+                state.under_pressure = fill >= MAP_PRESSURE_THRESHOLD;
+            }
+        }
+
+        for (prog_name, map_name, stats) in warnings {
+            self.warn_pressure(&prog_name, &map_name, &stats).await;
+        }
+    }
+
+    async fn warn_pressure(&self, prog_name: &str, map_name: &str, stats: &MapStats) {
+        let note = format!(
+            "eBPF map {} of program {} is at {}/{} entries, new entries will evict existing ones",
+            map_name, prog_name, stats.entries, stats.max_entries
+        );
+        warn!("{}", note);
+
+        let node_name = match std::env::var(NODE_NAME_ENV) {
+            Ok(name) => name,
+            Err(_) => return,
+        };
+        let client = match Client::try_default().await {
+            Ok(client) => client,
+            Err(e) => {
+                debug!("Failed to create kube client for events: {:?}", e);
+                return;
+            }
+        };
+        let reporter = Reporter {
+            controller: "ebpf-conductor-agent".to_string(),
+            instance: Some(node_name.clone()),
+        };
+        let reference = ObjectReference {
+            api_version: Some("v1".to_string()),
+            kind: Some("Node".to_string()),
+            name: Some(node_name),
+            ..Default::default()
+        };
+        let recorder = Recorder::new(client, reporter, reference);
+        if let Err(e) = recorder
+            .publish(Event {
+                type_: EventType::Warning,
+                reason: "MapPressure".to_string(),
+                note: Some(note),
+                action: "Sample".to_string(),
+                secondary: None,
+            })
+            .await
+        {
+            debug!("Failed to publish map pressure event: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evicted_keys() {
+        let keys = |range: std::ops::Range<u64>| range.collect::<AHashSet<u64>>();
+        // Deletes of the program with room left are not evictions.
+        assert_eq!(evicted_keys(&keys(0..8), &keys(4..10), 10), 0);
+        // A full map evicts a key per new key.
+        assert_eq!(evicted_keys(&keys(0..10), &keys(3..13), 10), 3);
+        // Only the new keys beyond the room left need evictions.
+        assert_eq!(evicted_keys(&keys(0..8), &keys(5..15), 10), 5);
+    }
+}
@@ -0,0 +1,394 @@
+use std::sync::Arc;
+use std::time::{Instant, SystemTime};
+
+use ahash::AHashMap;
+use clap::ValueEnum;
+use log::error;
+use parking_lot::RwLock;
+use prometheus_client::encoding::{DescriptorEncoder, EncodeMetric};
+use prometheus_client::metrics::counter::ConstCounter;
+use prometheus_client::metrics::gauge::ConstGauge;
+use prometheus_client::metrics::MetricType;
+use prometheus_client::registry::Unit;
+
+use crate::collector::agent::AGENT_METRICS;
+use crate::managers::cardinality::{label_sets, CardinalityLimits};
+use crate::progs::types::Program;
+
+/// How the metrics of a program are told apart from those of other programs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub(crate) enum MetricNamespace {
+    /// Adds a `program` label with the program name to every sample.
+    #[default]
+    Label,
+    /// Prefixes every metric name with the program name.
+    Prefix,
+    /// Leaves metrics as the program collects them.
+    None,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MetricKind {
+    Gauge,
+    /// A monotonic total. Samples may carry the time the series was created, pushed over
+    /// OTLP as the start time so that consumers can tell a reset from a new series.
+    Counter,
+    /// Cumulative observations in buckets. Samples carry their buckets in `histogram`,
+    /// and the number of observations as their value.
+    Histogram,
+}
+
+/// Observations of a histogram sample. Bucket counts are not cumulative, and the last
+/// bucket is the `+Inf` bucket, with an upper bound of `f64::MAX` as in prometheus-client.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct HistogramValue {
+    pub sum: f64,
+    pub count: u64,
+    pub buckets: Vec<(f64, u64)>,
+}
+
+impl HistogramValue {
+    pub(crate) fn new(bounds: &[f64]) -> Self {
+        Self {
+            sum: 0.0,
+            count: 0,
+            buckets: bounds
+                .iter()
+                .copied()
+                .chain([f64::MAX])
+                .map(|bound| (bound, 0))
+                .collect(),
+        }
+    }
+
+    pub(crate) fn observe(&mut self, value: f64) {
+        self.sum += value;
+        self.count += 1;
+        if let Some((_, count)) = self.buckets.iter_mut().find(|(bound, _)| value <= *bound) {
+            *count += 1;
+        }
+    }
+
+    /// Adds the observations of `other`, which must have the same buckets.
+    pub(crate) fn merge(&mut self, other: &HistogramValue) {
+        self.sum += other.sum;
+        self.count += other.count;
+        for ((_, count), (_, other_count)) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *count += other_count;
+        }
+    }
+}
+
+/// A single value of a metric family, identified by its labels.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Sample {
+    pub labels: Vec<(String, String)>,
+    pub value: f64,
+    pub created: Option<SystemTime>,
+    pub histogram: Option<HistogramValue>,
+}
+
+impl Sample {
+    pub(crate) fn new<'a>(labels: impl IntoIterator<Item = (&'a str, String)>, value: f64) -> Self {
+        Self {
+            labels: labels
+                .into_iter()
+                .map(|(key, value)| (key.to_string(), value))
+                .collect(),
+            value,
+            created: None,
+            histogram: None,
+        }
+    }
+
+    pub(crate) fn histogram<'a>(
+        labels: impl IntoIterator<Item = (&'a str, String)>,
+        histogram: HistogramValue,
+    ) -> Self {
+        let mut sample = Self::new(labels, histogram.count as f64);
+        sample.histogram = Some(histogram);
+        sample
+    }
+
+    pub(crate) fn with_created(mut self, created: SystemTime) -> Self {
+        self.created = Some(created);
+        self
+    }
+}
+
+/// A metric family as collected by a program, independent of any export format.
+#[derive(Clone, Debug)]
+pub(crate) struct MetricFamily {
+    pub name: String,
+    pub help: String,
+    pub unit: Option<Unit>,
+    pub kind: MetricKind,
+    pub samples: Vec<Sample>,
+}
+
+impl MetricFamily {
+    pub(crate) fn new(name: &str, help: &str, unit: Option<Unit>, kind: MetricKind) -> Self {
+        Self {
+            name: name.to_string(),
+            help: help.to_string(),
+            unit,
+            kind,
+            samples: vec![],
+        }
+    }
+
+    /// Returns the name of the family with its unit suffix.
+    fn full_name(&self) -> String {
+        match self.unit.as_ref() {
+            Some(unit) => format!("{}_{}", self.name, unit.as_str()),
+            None => self.name.clone(),
+        }
+    }
+
+    /// Returns the name of the samples of the family as rendered on `/metrics`.
+    pub(crate) fn sample_name(&self) -> String {
+        match self.kind {
+            MetricKind::Gauge | MetricKind::Histogram => self.full_name(),
+            MetricKind::Counter => format!("{}_total", self.full_name()),
+        }
+    }
+
+    pub(crate) fn encode(&self, encoder: &mut DescriptorEncoder) -> Result<(), std::fmt::Error> {
+        let metric_type = match self.kind {
+            MetricKind::Gauge => MetricType::Gauge,
+            MetricKind::Counter => MetricType::Counter,
+            MetricKind::Histogram => MetricType::Histogram,
+        };
+        let mut family_encoder =
+            encoder.encode_descriptor(&self.name, &self.help, self.unit.as_ref(), metric_type)?;
+        for sample in self.samples.iter() {
+            let mut metric_encoder = family_encoder.encode_family(&sample.labels)?;
+            match (self.kind, sample.histogram.as_ref()) {
+                (MetricKind::Histogram, Some(histogram)) => metric_encoder
+                    .encode_histogram::<Vec<(String, String)>>(
+                        histogram.sum,
+                        histogram.count,
+                        &histogram.buckets,
+                        None,
+                    )?,
+                (MetricKind::Histogram, None) => {}
+                (MetricKind::Counter, _) => {
+                    ConstCounter::new(sample.value).encode(metric_encoder)?
+                }
+                (MetricKind::Gauge, _) => ConstGauge::new(sample.value).encode(metric_encoder)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Stores the latest metrics snapshot of every running program. Programs write into
+/// the store on their own poll interval, and exporters only read from it, so serving
+/// metrics never changes program state.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct MetricsManager {
+    namespace: MetricNamespace,
+    limits: CardinalityLimits,
+    snapshots: Arc<RwLock<AHashMap<String, Arc<Vec<MetricFamily>>>>>,
+}
+
+impl MetricsManager {
+    pub(crate) fn new(namespace: MetricNamespace, limits: CardinalityLimits) -> Self {
+        Self {
+            namespace,
+            limits,
+            snapshots: Arc::new(RwLock::new(AHashMap::new())),
+        }
+    }
+
+    pub(crate) fn limits(&self) -> &CardinalityLimits {
+        &self.limits
+    }
+
+    /// Collects the metrics of `prog` and replaces its snapshot. On failure the previous
+    /// snapshot is kept.
+    pub(crate) fn collect(&self, prog: &dyn Program) -> Result<(), anyhow::Error> {
+        let name = prog.get_name();
+        let start = Instant::now();
+        let res = prog.collect();
+        AGENT_METRICS.record_collect(&name, start.elapsed(), res.is_err());
+        let families = res.map_err(|e| {
+            error!("Failed to collect metrics of {}: {:?}", name, e);
+            e
+        })?;
+        self.update(&name, families);
+        Ok(())
+    }
+
+    pub(crate) fn update(&self, program: &str, mut families: Vec<MetricFamily>) {
+        for family in families.iter_mut() {
+            match self.namespace {
+                MetricNamespace::Label => {
+                    for sample in family.samples.iter_mut() {
+                        if !sample.labels.iter().any(|(key, _)| key == "program") {
+                            sample
+                                .labels
+                                .insert(0, ("program".to_string(), program.to_string()));
+                        }
+                    }
+                }
+                MetricNamespace::Prefix => {
+                    family.name = format!("{}_{}", metric_name_prefix(program), family.name);
+                }
+                MetricNamespace::None => {}
+            }
+        }
+        let mut snapshots = self.snapshots.write();
+        let other_series = snapshots
+            .iter()
+            .filter(|(name, _)| name.as_str() != program)
+            .map(|(_, families)| label_sets(families))
+            .sum();
+        let dropped = self.limits.apply(program, &mut families, other_series);
+        if dropped > 0 {
+            AGENT_METRICS.record_series_dropped(program, dropped);
+        }
+        snapshots.insert(program.to_string(), Arc::new(families));
+    }
+
+    pub(crate) fn remove(&self, program: &str) {
+        self.snapshots.write().remove(program);
+        self.limits.forget(program);
+    }
+
+    /// Returns the latest snapshot of `program`, if it is running.
+    pub(crate) fn snapshot(&self, program: &str) -> Option<Arc<Vec<MetricFamily>>> {
+        self.snapshots.read().get(program).cloned()
+    }
+
+    /// Returns the latest snapshot of every program, ordered by program name.
+    pub(crate) fn snapshots(&self) -> Vec<(String, Arc<Vec<MetricFamily>>)> {
+        let mut snapshots: Vec<_> = self
+            .snapshots
+            .read()
+            .iter()
+            .map(|(program, snapshot)| (program.clone(), snapshot.clone()))
+            .collect();
+        snapshots.sort_by(|a, b| a.0.cmp(&b.0));
+        snapshots
+    }
+}
+
+/// Turns a program name into a valid metric name prefix.
+fn metric_name_prefix(program: &str) -> String {
+    program
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::UNIX_EPOCH;
+
+    use prometheus_client::collector::Collector;
+    use prometheus_client::encoding::text::encode;
+    use prometheus_client::registry::Registry;
+
+    use super::*;
+
+    #[derive(Debug)]
+    struct StoreCollector(MetricsManager);
+
+    impl Collector for StoreCollector {
+        fn encode(&self, mut encoder: DescriptorEncoder) -> Result<(), std::fmt::Error> {
+            for (_, families) in self.0.snapshots() {
+                for family in families.iter() {
+                    family.encode(&mut encoder)?;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_encode_snapshot() {
+        let store = MetricsManager::new(MetricNamespace::Label, CardinalityLimits::default());
+        let mut family = MetricFamily::new(
+            "connection_observed",
+            "total bytes_sent value of connections observed",
+            Some(Unit::Bytes),
+            MetricKind::Gauge,
+        );
+        family
+            .samples
+            .push(Sample::new([("role", "client".to_string())], 42.0));
+        store.update("service-map", vec![family]);
+
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(StoreCollector(store.clone())));
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer
+            .contains("connection_observed_bytes{program=\"service-map\",role=\"client\"} 42.0"));
+
+        store.remove("service-map");
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(!buffer.contains("connection_observed_bytes"));
+    }
+
+    #[test]
+    fn test_encode_counter() {
+        let mut family = MetricFamily::new(
+            "connection_sent",
+            "total bytes sent by connections observed",
+            Some(Unit::Bytes),
+            MetricKind::Counter,
+        );
+        family.samples.push(
+            Sample::new([("role", "client".to_string())], 42.0)
+                .with_created(UNIX_EPOCH + std::time::Duration::from_secs(1700000000)),
+        );
+        let store = MetricsManager::new(MetricNamespace::None, CardinalityLimits::default());
+        store.update("service-map", vec![family]);
+
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(StoreCollector(store)));
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains("# TYPE connection_sent_bytes counter"));
+        assert!(buffer.contains("connection_sent_bytes_total{role=\"client\"} 42.0"));
+        // prometheus-client can't render `_created` samples, and a separate family of
+        // that name would clash with the counter, so the created time is only pushed.
+        assert!(!buffer.contains("_created"));
+    }
+
+    #[test]
+    fn test_encode_histogram() {
+        let mut histogram = HistogramValue::new(&[0.01, 0.1]);
+        histogram.observe(0.005);
+        histogram.observe(0.05);
+        histogram.observe(2.0);
+        let mut family = MetricFamily::new(
+            "connection_handshake_duration",
+            "time from SYN_SENT to ESTABLISHED of client connections",
+            Some(Unit::Seconds),
+            MetricKind::Histogram,
+        );
+        family.samples.push(Sample::histogram(
+            [("role", "client".to_string())],
+            histogram,
+        ));
+        let store = MetricsManager::new(MetricNamespace::None, CardinalityLimits::default());
+        store.update("service-map", vec![family]);
+
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(StoreCollector(store)));
+        let mut buffer = String::new();
+        encode(&mut buffer, &registry).unwrap();
+        assert!(buffer.contains(
+            "connection_handshake_duration_seconds_bucket{le=\"0.1\",role=\"client\"} 2"
+        ));
+        assert!(buffer.contains(
+            "connection_handshake_duration_seconds_bucket{le=\"+Inf\",role=\"client\"} 3"
+        ));
+        assert!(buffer.contains("connection_handshake_duration_seconds_count{role=\"client\"} 3"));
+    }
+}
@@ -1,4 +1,7 @@
 pub(crate) mod cache;
+pub(crate) mod cardinality;
 pub(crate) mod image;
+pub(crate) mod map;
+pub(crate) mod metrics;
 pub(crate) mod prog;
 pub(crate) mod registry;
@@ -1,6 +1,8 @@
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
 
+use bpfman_lib::directories::RTDIR_FS_MAPS;
 use log::{debug, error, info};
 use parking_lot::Mutex;
 use tokio::sync::broadcast;
@@ -9,9 +11,14 @@ use tokio::task::JoinHandle;
 use agent_api::ProgramState;
 use agent_api::ProgramType;
 
+use crate::collector::agent::AGENT_METRICS;
+use crate::common::bpf::dump_pinned_map;
+use crate::common::error::AgentError;
 use crate::common::types::ListFilter;
 use crate::managers::cache::CacheManager;
 use crate::managers::image::ImageManager;
+use crate::managers::map::MapManager;
+use crate::managers::metrics::MetricsManager;
 use crate::managers::registry::RegistryManager;
 use crate::progs::types::{Program, ShutdownSignal};
 
@@ -19,6 +26,8 @@ use crate::progs::types::{Program, ShutdownSignal};
 pub(crate) struct ProgManager {
     pub cache_manager: CacheManager,
     pub image_manager: ImageManager,
+    pub map_manager: MapManager,
+    pub metrics_manager: MetricsManager,
     pub registry_manager: RegistryManager,
     pub program_handles: Arc<Mutex<HashMap<String, JoinHandle<()>>>>,
     pub shutdown_tx: broadcast::Sender<ShutdownSignal>,
@@ -27,13 +36,17 @@ pub(crate) struct ProgManager {
 impl ProgManager {
     pub(crate) async fn new(
         shutdown_tx: broadcast::Sender<ShutdownSignal>,
+        metrics_manager: MetricsManager,
     ) -> anyhow::Result<ProgManager> {
+        let registry_manager = RegistryManager::new();
+        metrics_manager.limits().check_programs(&registry_manager)?;
         let cache_manager = CacheManager::new().await?;
-        cache_manager.wait_for_cache_sync().await?;
         Ok(Self {
             cache_manager,
             image_manager: ImageManager::new(),
-            registry_manager: RegistryManager::new(),
+            map_manager: MapManager::new(registry_manager.clone()),
+            metrics_manager,
+            registry_manager,
             program_handles: Arc::new(Mutex::new(HashMap::new())),
             shutdown_tx,
         })
@@ -46,25 +59,31 @@ impl ProgManager {
         metadata: HashMap<String, String>,
         cache_manager: CacheManager,
         map_to_prog_id: HashMap<String, u32>,
-    ) -> Result<Arc<dyn Program>, anyhow::Error> {
+    ) -> Result<Arc<dyn Program>, AgentError> {
         let prog = match self.get(program_name.clone(), Some(program_type)).await {
             Some(p) => p,
             None => {
-                let err_msg = format!("Program {} not found.", program_name);
-                error!("{}", &err_msg);
-                return Err(anyhow::Error::msg(err_msg));
+                error!("Program {} not found.", program_name);
+                return Err(AgentError::ProgramNotFound { name: program_name });
             }
         };
         match prog.get_state() {
-            ProgramState::Uninitialized => match prog.init(metadata, cache_manager, map_to_prog_id)
-            {
+            ProgramState::Uninitialized => match prog.init(
+                metadata,
+                cache_manager,
+                self.metrics_manager.clone(),
+                map_to_prog_id,
+            ) {
                 Ok(()) => {
                     prog.set_state(ProgramState::Initialized);
                     info!("Program {} initialized successfully.", prog.get_name());
                 }
                 Err(e) => {
                     error!("Failed to initialize program {}: {:?}", prog.get_name(), e);
-                    return Err(e);
+                    return Err(AgentError::InitFailed {
+                        name: prog.get_name(),
+                        source: e,
+                    });
                 }
             },
             _ => {
@@ -88,11 +107,13 @@ impl ProgManager {
         self.registry_manager.list_programs(list_filter)
     }
 
-    pub(crate) async fn load(&self, prog: Arc<dyn Program>) -> Result<(), anyhow::Error> {
+    pub(crate) async fn load(&self, prog: Arc<dyn Program>) -> Result<(), AgentError> {
         match prog.get_state() {
             ProgramState::Initialized => {
                 let shutdown_rx = self.shutdown_tx.subscribe();
                 let p = prog.clone();
+                let metrics_manager = self.metrics_manager.clone();
+                AGENT_METRICS.record_program_start(&prog.get_name());
                 let handle = tokio::spawn(async move {
                     p.set_state(ProgramState::Running);
                     match p.start(shutdown_rx).await {
@@ -109,33 +130,41 @@ impl ProgManager {
                             )
                         }
                     }
+                    metrics_manager.remove(&p.get_name());
                 });
 
                 let mut handlers = self.program_handles.lock();
                 handlers.insert(prog.get_name(), handle);
             }
-            _ => {
-                let err_msg = format!(
+            ProgramState::Running => {
+                debug!("Program {} is already running.", prog.get_name());
+                return Err(AgentError::AlreadyRunning {
+                    name: prog.get_name(),
+                });
+            }
+            state => {
+                debug!(
                     "Program {} is in an invalid state to be loaded: {:?}",
                     prog.get_name(),
-                    prog.get_state()
+                    state
                 );
-                debug!("{}", &err_msg);
-                return Err(anyhow::Error::msg(err_msg));
+                return Err(AgentError::InvalidState {
+                    name: prog.get_name(),
+                    state: format!("{:?}", state),
+                });
             }
         }
 
         Ok(())
     }
 
-    pub(crate) async fn unload(&self, program_name: String) -> Result<(), anyhow::Error> {
+    pub(crate) async fn unload(&self, program_name: String) -> Result<(), AgentError> {
         let program = self
             .registry_manager
             .get_program(program_name.as_str(), None)
-            .ok_or(anyhow::Error::msg(format!(
-                "Failed to get program {} for unloading.",
-                program_name
-            )))?;
+            .ok_or_else(|| AgentError::ProgramNotFound {
+                name: program_name.clone(),
+            })?;
 
         program.stop().await?;
 
@@ -169,4 +198,43 @@ impl ProgManager {
 
         Ok(())
     }
+
+    pub(crate) async fn dump_map(
+        &self,
+        program_name: String,
+        map_name: String,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>, AgentError> {
+        let program = self
+            .registry_manager
+            .get_program(program_name.as_str(), None)
+            .ok_or_else(|| AgentError::ProgramNotFound {
+                name: program_name.clone(),
+            })?;
+        let prog_id = program
+            .get_program_info()?
+            .ebpf_maps
+            .get(&map_name)
+            .copied()
+            .ok_or_else(|| AgentError::MapNotBound {
+                program: program_name.clone(),
+                map: map_name.clone(),
+            })?;
+
+        let map_pin_path = Path::new(RTDIR_FS_MAPS).join(format!("{}/{}", prog_id, map_name));
+        let entries =
+            dump_pinned_map(&map_pin_path, limit).map_err(|source| AgentError::MapRead {
+                path: map_pin_path.display().to_string(),
+                source,
+            })?;
+
+        Ok(entries
+            .into_iter()
+            .map(|(key, value)| {
+                program
+                    .decode_map_entry(&map_name, &key, &value)
+                    .unwrap_or_else(|| (hex::encode(&key), hex::encode(&value)))
+            })
+            .collect())
+    }
 }
@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use anyhow::{anyhow, Error};
+
+use crate::managers::cache::Workload;
+
+const EXTERNAL_NAMESPACE: &str = "external";
+const EXTERNAL_KIND: &str = "External";
+
+/// How IPs that are neither in the cluster nor in a named CIDR are attributed, as set by
+/// the `unresolved_ips` metadata of the program.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) enum UnresolvedIps {
+    /// A single `external` workload.
+    #[default]
+    External,
+    /// One workload per IP.
+    Raw,
+    /// One workload per /24 IPv4 or /64 IPv6 subnet.
+    Subnet,
+    /// Connections are dropped.
+    Drop,
+}
+
+impl FromStr for UnresolvedIps {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "external" => Ok(Self::External),
+            "raw" => Ok(Self::Raw),
+            "subnet" => Ok(Self::Subnet),
+            "drop" => Ok(Self::Drop),
+            _ => Err(anyhow!(
+                "unresolved_ips must be one of external, raw, subnet or drop, got {}",
+                s
+            )),
+        }
+    }
+}
+
+/// An IPv4 or IPv6 network. IPv4 networks are kept as IPv4-mapped IPv6 networks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Cidr {
+    addr: u128,
+    prefix: u32,
+}
+
+impl Cidr {
+    fn contains(&self, ip: IpAddr) -> bool {
+        ip_bits(ip) & prefix_mask(self.prefix) == self.addr
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').ok_or(anyhow!("{} is not a CIDR", s))?;
+        let addr: IpAddr = addr.parse()?;
+        let prefix: u32 = prefix.parse()?;
+        let prefix = match addr {
+            IpAddr::V4(_) if prefix <= 32 => prefix + 96,
+            IpAddr::V6(_) if prefix <= 128 => prefix,
+            _ => return Err(anyhow!("{} has an invalid prefix length", s)),
+        };
+        Ok(Self {
+            addr: ip_bits(addr) & prefix_mask(prefix),
+            prefix,
+        })
+    }
+}
+
+fn ip_bits(ip: IpAddr) -> u128 {
+    let ip = match ip.to_canonical() {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    };
+    u128::from(ip)
+}
+
+fn prefix_mask(prefix: u32) -> u128 {
+    u128::MAX.checked_shl(128 - prefix).unwrap_or(0)
+}
+
+/// Attributes IPs unknown to the cache manager to named CIDRs, or to `External`
+/// workloads.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ExternalResolver {
+    cidrs: Vec<(Cidr, Arc<Workload>)>,
+    unresolved_ips: UnresolvedIps,
+}
+
+impl ExternalResolver {
+    /// Reads the `cidrs` metadata, a comma separated list of `name=cidr` such as
+    /// `corp-db=10.20.0.0/16,metadata-service=169.254.169.254/32`, and the
+    /// `unresolved_ips` metadata.
+    pub(crate) fn from_metadata(metadata: &HashMap<String, String>) -> Result<Self, Error> {
+        let mut cidrs = vec![];
+        for entry in metadata
+            .get("cidrs")
+            .map(|cidrs| cidrs.split(','))
+            .into_iter()
+            .flatten()
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (name, cidr) = entry
+                .split_once('=')
+                .ok_or(anyhow!("Invalid cidrs entry {}, expected name=cidr", entry))?;
+            cidrs.push((cidr.trim().parse()?, external_workload(name.trim())));
+        }
+        // The most specific CIDR wins.
+        cidrs.sort_by_key(|(cidr, _): &(Cidr, _)| std::cmp::Reverse(cidr.prefix));
+
+        let unresolved_ips = match metadata.get("unresolved_ips") {
+            Some(unresolved_ips) => unresolved_ips.parse()?,
+            None => UnresolvedIps::default(),
+        };
+        Ok(Self {
+            cidrs,
+            unresolved_ips,
+        })
+    }
+
+    pub(crate) fn resolve(&self, ip: IpAddr) -> Option<Arc<Workload>> {
+        if let Some((_, workload)) = self.cidrs.iter().find(|(cidr, _)| cidr.contains(ip)) {
+            return Some(workload.clone());
+        }
+        match self.unresolved_ips {
+            UnresolvedIps::External => Some(external_workload(EXTERNAL_NAMESPACE)),
+            UnresolvedIps::Raw => Some(external_workload(&ip.to_canonical().to_string())),
+            UnresolvedIps::Subnet => Some(external_workload(&subnet(ip))),
+            UnresolvedIps::Drop => None,
+        }
+    }
+}
+
+fn external_workload(name: &str) -> Arc<Workload> {
+    Arc::new(Workload {
+        name: name.to_string(),
+        namespace: EXTERNAL_NAMESPACE.to_string(),
+        kind: EXTERNAL_KIND.to_string(),
+    })
+}
+
+fn subnet(ip: IpAddr) -> String {
+    match ip.to_canonical() {
+        IpAddr::V4(ip) => format!("{}/24", Ipv4Addr::from(u32::from(ip) & 0xffff_ff00)),
+        IpAddr::V6(ip) => format!("{}/64", Ipv6Addr::from(u128::from(ip) & prefix_mask(64))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_external_ips() {
+        let metadata = HashMap::from([
+            (
+                "cidrs".to_string(),
+                "corp=10.0.0.0/8, corp-db=10.20.0.0/16,metadata-service=169.254.169.254/32"
+                    .to_string(),
+            ),
+            ("unresolved_ips".to_string(), "subnet".to_string()),
+        ]);
+        let resolver = ExternalResolver::from_metadata(&metadata).unwrap();
+
+        let name = |ip: &str| resolver.resolve(ip.parse().unwrap()).unwrap().name.clone();
+        assert_eq!(name("10.20.1.2"), "corp-db");
+        assert_eq!(name("::ffff:10.1.1.1"), "corp");
+        assert_eq!(name("169.254.169.254"), "metadata-service");
+        assert_eq!(name("203.0.113.7"), "203.0.113.0/24");
+        assert_eq!(name("2001:db8::1"), "2001:db8::/64");
+
+        let resolver = ExternalResolver::from_metadata(&HashMap::new()).unwrap();
+        assert_eq!(
+            resolver
+                .resolve("203.0.113.7".parse().unwrap())
+                .unwrap()
+                .kind,
+            "External"
+        );
+        assert!(ExternalResolver::from_metadata(&HashMap::from([(
+            "cidrs".to_string(),
+            "corp=10.0.0.0/33".to_string()
+        )]))
+        .is_err());
+    }
+}
@@ -1,19 +1,16 @@
 use std::cmp::PartialEq;
-use std::collections::HashMap;
-use std::net::Ipv4Addr;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 
-use anyhow::Error;
+use anyhow::{Context, Error};
 use async_trait::async_trait;
 use aya::maps::{HashMap as AyaHashMap, Map, MapData};
 use bpfman_lib::directories::RTDIR_FS_MAPS;
 use log::debug;
 use parking_lot::RwLock;
-use prometheus_client::encoding::{DescriptorEncoder, EncodeLabelSet, EncodeMetric};
-use prometheus_client::metrics::family::Family;
-use prometheus_client::metrics::gauge::Gauge;
 use prometheus_client::registry::Unit;
 use tokio::sync::broadcast;
 use tokio::time;
@@ -25,17 +22,130 @@ use conn_tracer_common::{
     CONNECTION_ROLE_UNKNOWN,
 };
 
-use crate::common::constants::DEFAULT_INTERVAL;
-use crate::common::utils::fnv_hash;
-use crate::managers::cache::{CacheManager, Workload};
+use crate::collector::agent::AGENT_METRICS;
+use crate::common::btf::{type_layout, validate_pinned_map, MapLayout};
+use crate::common::constants::{DEFAULT_EDGE_TTL, DEFAULT_INTERVAL};
+use crate::common::utils::{fnv_hash, read_pod};
+use crate::managers::cache::{CacheManager, PortName, Workload};
+use crate::managers::metrics::{HistogramValue, MetricFamily, MetricKind, MetricsManager, Sample};
+use crate::progs::service_map::external::ExternalResolver;
 use crate::progs::types::{Program, ShutdownSignal};
 
+/// Upper bounds of the handshake latency buckets, in seconds.
+const HANDSHAKE_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 3.0,
+];
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub(crate) struct Connection {
     client: Arc<Workload>,
     server: Arc<Workload>,
     role: u32,
     server_port: u32,
+    server_port_name: Option<Arc<PortName>>,
+}
+
+/// Cumulative values of a connection, from the point of view of the socket that `role`
+/// refers to: for clients `sent` is the request direction, for servers the response
+/// direction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct ConnectionTotals {
+    sent: u64,
+    received: u64,
+    retransmits: u64,
+}
+
+impl ConnectionTotals {
+    fn add(&mut self, other: ConnectionTotals) {
+        self.sent += other.sent;
+        self.received += other.received;
+        self.retransmits += other.retransmits;
+    }
+
+    fn max(&mut self, other: ConnectionTotals) {
+        self.sent = self.sent.max(other.sent);
+        self.received = self.received.max(other.received);
+        self.retransmits = self.retransmits.max(other.retransmits);
+    }
+}
+
+/// Smoothed RTTs of the open connections of an edge, in seconds.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RttStats {
+    min: f64,
+    max: f64,
+    sum: f64,
+    count: u64,
+}
+
+impl RttStats {
+    fn new(rtt: f64) -> Self {
+        Self {
+            min: rtt,
+            max: rtt,
+            sum: rtt,
+            count: 1,
+        }
+    }
+
+    fn observe(&mut self, rtt: f64) {
+        self.min = self.min.min(rtt);
+        self.max = self.max.max(rtt);
+        self.sum += rtt;
+        self.count += 1;
+    }
+}
+
+/// What a single poll saw of a client→server edge. `opened` and `closed` only count the
+/// connections whose open or close was first seen in this poll.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct EdgeStats {
+    totals: ConnectionTotals,
+    rtt: Option<RttStats>,
+    lost: u64,
+    active: u64,
+    opened: u64,
+    closed: u64,
+    failed: u64,
+    handshakes: Vec<f64>,
+}
+
+/// The counters reported for a client→server edge. They never decrease, even when
+/// connections are evicted from the map before their close is seen, and keep being
+/// reported once all connections of the edge are closed, until the edge has been idle
+/// for the edge TTL.
+#[derive(Debug, Clone)]
+struct EdgeCounters {
+    created: SystemTime,
+    last_active: SystemTime,
+    totals: ConnectionTotals,
+    rtt: Option<RttStats>,
+    lost: u64,
+    active: u64,
+    opened: u64,
+    closed: u64,
+    failed: u64,
+    handshake: HistogramValue,
+}
+
+/// The entries of the connections map seen by a poll, grouped by edge.
+#[derive(Debug, Default)]
+struct PolledConnections {
+    edges: HashMap<Connection, EdgeStats>,
+    /// Connections still open, so the next poll can tell which ones it sees first.
+    open: HashSet<ConnectionKey>,
+    /// Connections to move to the past connections once the poll is done.
+    closed: Vec<ConnectionKey>,
+}
+
+impl From<&ConnectionStats> for ConnectionTotals {
+    fn from(stats: &ConnectionStats) -> Self {
+        Self {
+            sent: stats.bytes_sent,
+            received: stats.bytes_received,
+            retransmits: stats.total_retrans,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -46,8 +156,16 @@ struct Inner {
     ebpf_maps: HashMap<String, u32>,
     metadata: HashMap<String, String>,
     current_conns_map: Option<AyaHashMap<MapData, ConnectionKey, ConnectionStats>>,
-    past_conns_map: HashMap<Connection, u64>,
+    past_conns_map: HashMap<Connection, ConnectionTotals>,
+    edges: HashMap<Connection, EdgeCounters>,
+    /// Connections the last poll saw open, or `None` before the first poll, whose
+    /// connections may have been opened long before.
+    open_conns: Option<HashSet<ConnectionKey>>,
     cache_mgr: Option<CacheManager>,
+    external: Arc<ExternalResolver>,
+    join_backends: bool,
+    edge_ttl: Duration,
+    metrics_mgr: Option<MetricsManager>,
 }
 
 impl Inner {
@@ -60,9 +178,67 @@ impl Inner {
             metadata: HashMap::new(),
             current_conns_map: None,
             past_conns_map: HashMap::new(),
+            edges: HashMap::new(),
+            open_conns: None,
             cache_mgr: None,
+            external: Arc::new(ExternalResolver::default()),
+            join_backends: false,
+            edge_ttl: Duration::from_secs(DEFAULT_EDGE_TTL),
+            metrics_mgr: None,
         }
     }
+
+    /// Adds the edges seen by a poll at `now` to the reported counters, and forgets the
+    /// edges idle for longer than the edge TTL. An edge seen again later is a new
+    /// series, with a new created time.
+    fn record_poll(&mut self, conns: HashMap<Connection, EdgeStats>, now: SystemTime) {
+        for edge in self.edges.values_mut() {
+            edge.rtt = None;
+            edge.lost = 0;
+            edge.active = 0;
+        }
+        for (conn, stats) in conns {
+            let edge = self.edges.entry(conn).or_insert(EdgeCounters {
+                created: now,
+                last_active: now,
+                totals: ConnectionTotals::default(),
+                rtt: None,
+                lost: 0,
+                active: 0,
+                opened: 0,
+                closed: 0,
+                failed: 0,
+                handshake: HistogramValue::new(&HANDSHAKE_BUCKETS),
+            });
+            let mut totals = edge.totals;
+            totals.max(stats.totals);
+            if stats.active > 0
+                || stats.opened > 0
+                || stats.closed > 0
+                || stats.failed > 0
+                || totals != edge.totals
+            {
+                edge.last_active = now;
+            }
+            edge.totals = totals;
+            edge.rtt = stats.rtt;
+            edge.lost = stats.lost;
+            edge.active = stats.active;
+            edge.opened += stats.opened;
+            edge.closed += stats.closed;
+            edge.failed += stats.failed;
+            for handshake in stats.handshakes {
+                edge.handshake.observe(handshake);
+            }
+        }
+
+        let ttl = self.edge_ttl;
+        self.edges
+            .retain(|_, edge| now.duration_since(edge.last_active).unwrap_or_default() <= ttl);
+        let edges = &self.edges;
+        self.past_conns_map
+            .retain(|conn, _| edges.contains_key(conn));
+    }
 }
 
 #[derive(Debug)]
@@ -81,11 +257,20 @@ impl ServiceMap {
         let mut inner = self.inner.write();
         inner.current_conns_map = None;
         inner.past_conns_map.clear();
+        inner.edges.clear();
+        inner.open_conns = None;
         inner.metadata.clear();
         inner.ebpf_maps.clear();
     }
 
-    fn poll(&self) -> Result<HashMap<Connection, u64>, Error> {
+    fn poll(&self) -> Result<HashMap<Connection, EdgeStats>, Error> {
+        let start = Instant::now();
+        let res = self.read_connections();
+        AGENT_METRICS.record_poll(&self.get_name(), start.elapsed());
+        res
+    }
+
+    fn read_connections(&self) -> Result<HashMap<Connection, EdgeStats>, Error> {
         let inner = self.inner.read();
         let tcp_conns_map = inner
             .current_conns_map
@@ -97,86 +282,179 @@ impl ServiceMap {
             .as_ref()
             .ok_or(Error::msg("No cache manager"))?
             .clone();
+        let external = inner.external.clone();
+        let join_backends = inner.join_backends;
 
-        let mut keys_to_remove = Vec::new();
-        let mut current_conns: HashMap<Connection, u64> = HashMap::new();
-
-        for item in tcp_conns_map.iter() {
-            let (key, stats) = item?;
-            if stats.is_active != 1 {
-                keys_to_remove.push(key);
-                continue;
-            }
-            if key.src_addr == key.dest_addr || self.is_loopback_address(key.dest_addr) {
-                continue;
-            }
-            if key.role == CONNECTION_ROLE_UNKNOWN {
-                continue;
-            }
-
-            if let Ok(connection) = self.build_connection(key, &cache_mgr) {
-                current_conns
-                    .entry(connection.clone())
-                    .and_modify(|e| *e += stats.bytes_sent)
-                    .or_insert(stats.bytes_sent);
-            }
-        }
+        let entries = tcp_conns_map.iter().collect::<Result<Vec<_>, _>>()?;
+        let PolledConnections {
+            edges: mut current_conns,
+            open: open_conns,
+            closed: keys_to_remove,
+        } = self.group_connections(
+            entries,
+            inner.open_conns.as_ref(),
+            &cache_mgr,
+            &external,
+            join_backends,
+        );
 
-        for (conn, bytes_sent) in past_conns_map.iter() {
+        for (conn, totals) in past_conns_map.iter() {
             current_conns
                 .entry(conn.clone())
-                .and_modify(|e| *e += *bytes_sent)
-                .or_insert(*bytes_sent);
+                .or_default()
+                .totals
+                .add(*totals);
         }
 
         // Release the read lock before removing inactive connections
         drop(inner);
 
         let mut inner = self.inner.write();
+        // Connections evicted from the map without a close are forgotten here too.
+        inner.open_conns = Some(open_conns);
         for key in keys_to_remove {
-            let _ = self.handle_inactive_connection(key, &mut inner, &cache_mgr);
+            let _ = self.handle_inactive_connection(
+                key,
+                &mut inner,
+                &cache_mgr,
+                &external,
+                join_backends,
+            );
         }
 
         Ok(current_conns)
     }
 
-    fn resolve_ip(&self, ip: u32, cache_mgr_ref: &CacheManager) -> Option<Arc<Workload>> {
-        let ip_to_workload_lock = cache_mgr_ref.ip_to_workload.clone();
-        let ip_to_workload = ip_to_workload_lock.read();
-        let ip_addr = Ipv4Addr::from(ip);
-        let ip_string = ip_addr.to_string();
-        ip_to_workload.get(&ip_string).cloned()
+    /// Groups the entries of the connections map by edge. `open_conns` are the
+    /// connections the previous poll saw open. Without a previous poll, no connection
+    /// counts as opened, as the map may hold connections from before the program was
+    /// loaded or the agent restarted.
+    fn group_connections(
+        &self,
+        entries: Vec<(ConnectionKey, ConnectionStats)>,
+        open_conns: Option<&HashSet<ConnectionKey>>,
+        cache_mgr: &CacheManager,
+        external: &ExternalResolver,
+        join_backends: bool,
+    ) -> PolledConnections {
+        let mut polled = PolledConnections::default();
+
+        for (key, stats) in entries {
+            // Closed connections still count towards this poll, as their bytes only
+            // move to the past connections once the poll is done.
+            let closed = stats.is_active != 1;
+            let opened = open_conns.is_some_and(|open_conns| !open_conns.contains(&key));
+            if closed {
+                polled.closed.push(key);
+            } else {
+                polled.open.insert(key);
+            }
+            if key.src_addr == key.dest_addr || self.is_loopback_address(key.dest_addr) {
+                continue;
+            }
+            if key.role == CONNECTION_ROLE_UNKNOWN {
+                continue;
+            }
+
+            if let Ok(connection) = self.build_connection(key, cache_mgr, external, join_backends) {
+                let edge = polled.edges.entry(connection).or_default();
+                edge.totals.add(ConnectionTotals::from(&stats));
+                // Failed connects were never open, so only count as failed.
+                if stats.connect_failed == 1 {
+                    edge.failed += 1;
+                    continue;
+                }
+                if closed {
+                    edge.closed += 1;
+                } else {
+                    edge.active += 1;
+                    edge.lost += stats.lost_out;
+                    if stats.srtt_us > 0 {
+                        let rtt = Self::rtt_seconds(stats.srtt_us);
+                        match edge.rtt.as_mut() {
+                            Some(rtt_stats) => rtt_stats.observe(rtt),
+                            None => edge.rtt = Some(RttStats::new(rtt)),
+                        }
+                    }
+                }
+                if opened {
+                    edge.opened += 1;
+                    if stats.handshake_ns > 0 {
+                        edge.handshakes
+                            .push(stats.handshake_ns as f64 / 1_000_000_000.0);
+                    }
+                }
+            }
+        }
+
+        polled
+    }
+
+    /// Converts the `srtt_us` kept by the kernel, in microseconds shifted left by 3, to
+    /// seconds.
+    fn rtt_seconds(srtt_us: u64) -> f64 {
+        (srtt_us >> 3) as f64 / 1_000_000.0
+    }
+
+    fn ip_addr(addr: [u8; 16]) -> IpAddr {
+        Ipv6Addr::from(addr).to_canonical()
     }
 
+    /// Builds the edge `key` belongs to. With `join_backends`, connections to a Service
+    /// are attributed to the workload backing it, and connections served to clients in
+    /// the cluster are left to the client side. That side is only observed if the node
+    /// of the client runs the agent with this program loaded.
     fn build_connection(
         &self,
         key: ConnectionKey,
         cache_mgr_ref: &CacheManager,
+        external: &ExternalResolver,
+        join_backends: bool,
     ) -> Result<Connection, Error> {
-        let client_workload = self
-            .resolve_ip(key.src_addr, cache_mgr_ref)
-            .ok_or(Error::msg(format!(
-                "Unknown IP: {}",
-                Ipv4Addr::from(key.src_addr)
-            )))?;
-        let server_workload = self
-            .resolve_ip(key.dest_addr, cache_mgr_ref)
-            .ok_or(Error::msg(format!(
-                "Unknown IP: {}",
-                Ipv4Addr::from(key.dest_addr)
-            )))?;
-
-        let (client, server, port) = match key.role {
-            CONNECTION_ROLE_CLIENT => (client_workload, server_workload, key.dest_port),
-            CONNECTION_ROLE_SERVER => (server_workload, client_workload, key.src_port),
+        let (client_addr, server_addr, port) = match key.role {
+            CONNECTION_ROLE_CLIENT => (
+                Self::ip_addr(key.src_addr),
+                Self::ip_addr(key.dest_addr),
+                key.dest_port,
+            ),
+            CONNECTION_ROLE_SERVER => (
+                Self::ip_addr(key.dest_addr),
+                Self::ip_addr(key.src_addr),
+                key.src_port,
+            ),
             _ => return Err(Error::msg("Unknown connection role")),
         };
+        let server_port_name = cache_mgr_ref.resolve_port(server_addr, port as u16);
+
+        let client = match cache_mgr_ref.resolve_ip(client_addr) {
+            Some(_) if join_backends && key.role == CONNECTION_ROLE_SERVER => {
+                return Err(Error::msg("Connection is observed by the client"));
+            }
+            Some(client) => client,
+            None => external
+                .resolve(client_addr)
+                .ok_or(Error::msg(format!("Unknown IP: {}", client_addr)))?,
+        };
+        let backend = join_backends
+            .then(|| cache_mgr_ref.resolve_backend(server_addr, port as u16))
+            .flatten();
+        let (server, port) = match backend {
+            Some((server, backend_port)) => (server, backend_port as u32),
+            None => (
+                cache_mgr_ref
+                    .resolve_ip(server_addr)
+                    .or_else(|| external.resolve(server_addr))
+                    .ok_or(Error::msg(format!("Unknown IP: {}", server_addr)))?,
+                port,
+            ),
+        };
 
         Ok(Connection {
             client,
             server,
             role: key.role,
             server_port: port,
+            server_port_name,
         })
     }
 
@@ -185,24 +463,51 @@ impl ServiceMap {
         key: ConnectionKey,
         inner: &mut Inner,
         cache_mgr_ref: &CacheManager,
+        external: &ExternalResolver,
+        join_backends: bool,
     ) -> Result<(), Error> {
-        let throughput = match inner.current_conns_map.as_mut().unwrap().get(&key, 0) {
-            Ok(stats) => stats.bytes_sent,
-            Err(_) => 0,
+        let totals = match inner.current_conns_map.as_mut().unwrap().get(&key, 0) {
+            Ok(stats) => ConnectionTotals::from(&stats),
+            Err(_) => ConnectionTotals::default(),
         };
         inner.current_conns_map.as_mut().unwrap().remove(&key)?;
-        let connection = self.build_connection(key, cache_mgr_ref)?;
+        let connection = self.build_connection(key, cache_mgr_ref, external, join_backends)?;
         inner
             .past_conns_map
             .entry(connection)
-            .and_modify(|e| *e += throughput)
-            .or_insert(throughput);
+            .or_default()
+            .add(totals);
         Ok(())
     }
 
-    fn is_loopback_address(&self, addr: u32) -> bool {
-        let ip_addr = Ipv4Addr::from(addr);
-        ip_addr.is_loopback()
+    fn is_loopback_address(&self, addr: [u8; 16]) -> bool {
+        Self::ip_addr(addr).is_loopback()
+    }
+
+    /// The layout of the `CONNECTIONS` map as declared by conn-tracer.
+    fn connections_layout() -> MapLayout {
+        MapLayout {
+            key: type_layout!(ConnectionKey {
+                id,
+                pid,
+                family,
+                src_addr,
+                src_port,
+                dest_addr,
+                dest_port,
+                role,
+            }),
+            value: type_layout!(ConnectionStats {
+                bytes_sent,
+                bytes_received,
+                is_active,
+                srtt_us,
+                total_retrans,
+                lost_out,
+                handshake_ns,
+                connect_failed,
+            }),
+        }
     }
 }
 
@@ -212,12 +517,23 @@ impl Program for ServiceMap {
         &self,
         metadata: HashMap<String, String>,
         cache_manager: CacheManager,
+        metrics_manager: MetricsManager,
         maps: HashMap<String, u32>,
     ) -> Result<(), Error> {
         let mut inner = self.inner.write();
         inner.ebpf_maps = maps.clone();
+        inner.external = Arc::new(ExternalResolver::from_metadata(&metadata)?);
+        inner.join_backends = match metadata.get("join_backends") {
+            Some(join_backends) => join_backends.parse()?,
+            None => false,
+        };
+        inner.edge_ttl = match metadata.get("edge_ttl") {
+            Some(edge_ttl) => Duration::from_secs(edge_ttl.parse()?),
+            None => Duration::from_secs(DEFAULT_EDGE_TTL),
+        };
         inner.metadata = metadata;
         inner.cache_mgr = Some(cache_manager);
+        inner.metrics_mgr = Some(metrics_manager);
 
         let map_name = "CONNECTIONS";
         let prog_id = maps.get(map_name).ok_or(anyhow::anyhow!(
@@ -229,6 +545,8 @@ impl Program for ServiceMap {
         }
 
         let map_pin_path = bpfman_maps.join(format!("{}/{}", prog_id, map_name));
+        validate_pinned_map(&map_pin_path, &Self::connections_layout())
+            .context("Map CONNECTIONS has an unexpected layout")?;
         let map_data = MapData::from_pin(map_pin_path)
             .map_err(|_| anyhow::anyhow!("No maps named CONNECTIONS"))?;
         let tcp_conns_map: AyaHashMap<MapData, ConnectionKey, ConnectionStats> =
@@ -248,14 +566,20 @@ impl Program for ServiceMap {
             .get("interval")
             .and_then(|i| i.parse::<u64>().ok())
             .unwrap_or(DEFAULT_INTERVAL);
+        let metrics_mgr = self
+            .inner
+            .read()
+            .metrics_mgr
+            .clone()
+            .ok_or(Error::msg("No metrics manager"))?;
 
         let mut interval = time::interval(Duration::from_secs(interval));
         loop {
             tokio::select! {
                 _ = interval.tick() => {
-                    if let Err(e) = self.poll() {
+                    if let Err(e) = metrics_mgr.collect(self) {
                         debug!("Error polling: {:?}", e);
-                        return Err(e.into());
+                        return Err(e);
                     }
                 }
                 Ok(signal) = shutdown_rx.recv() => {
@@ -281,50 +605,193 @@ impl Program for ServiceMap {
         Ok(())
     }
 
-    fn collect(&self, encoder: &mut DescriptorEncoder) -> Result<(), Error> {
+    fn collect(&self) -> Result<Vec<MetricFamily>, Error> {
         let conns = self.poll()?;
-        let conn_metric = Family::<Labels, Gauge>::default();
-        for (conn, value) in conns.iter() {
-            let labels = Labels {
-                conn_id: format!(
-                    "{:x}",
-                    fnv_hash(&format!(
-                        "{}{}{}{}",
-                        conn.client.name,
-                        conn.client.namespace,
-                        conn.server.name,
-                        conn.server.namespace
-                    ))
+        let mut inner = self.inner.write();
+        inner.record_poll(conns, SystemTime::now());
+
+        let mut sent_metric = MetricFamily::new(
+            "connection_sent",
+            "total bytes sent by connections observed",
+            Some(Unit::Bytes),
+            MetricKind::Counter,
+        );
+        let mut received_metric = MetricFamily::new(
+            "connection_received",
+            "total bytes received by connections observed",
+            Some(Unit::Bytes),
+            MetricKind::Counter,
+        );
+        let mut active_metric = MetricFamily::new(
+            "connection_active",
+            "number of connections currently open",
+            None,
+            MetricKind::Gauge,
+        );
+        let mut opened_metric = MetricFamily::new(
+            "connection_opened",
+            "number of connections opened",
+            None,
+            MetricKind::Counter,
+        );
+        let mut closed_metric = MetricFamily::new(
+            "connection_closed",
+            "number of connections closed",
+            None,
+            MetricKind::Counter,
+        );
+        let mut failed_metric = MetricFamily::new(
+            "connection_failed",
+            "number of connects refused or timed out before being established",
+            None,
+            MetricKind::Counter,
+        );
+        let mut handshake_metric = MetricFamily::new(
+            "connection_handshake_duration",
+            "time from SYN_SENT to ESTABLISHED of client connections",
+            Some(Unit::Seconds),
+            MetricKind::Histogram,
+        );
+        let mut retransmits_metric = MetricFamily::new(
+            "connection_retransmits",
+            "number of segments retransmitted by connections observed",
+            None,
+            MetricKind::Counter,
+        );
+        let mut lost_metric = MetricFamily::new(
+            "connection_lost_packets",
+            "number of packets of open connections currently presumed lost",
+            None,
+            MetricKind::Gauge,
+        );
+        let mut rtt_min_metric = MetricFamily::new(
+            "connection_rtt_min",
+            "lowest smoothed RTT of open connections",
+            Some(Unit::Seconds),
+            MetricKind::Gauge,
+        );
+        let mut rtt_avg_metric = MetricFamily::new(
+            "connection_rtt_avg",
+            "average smoothed RTT of open connections",
+            Some(Unit::Seconds),
+            MetricKind::Gauge,
+        );
+        let mut rtt_max_metric = MetricFamily::new(
+            "connection_rtt_max",
+            "highest smoothed RTT of open connections",
+            Some(Unit::Seconds),
+            MetricKind::Gauge,
+        );
+        for (conn, edge) in inner.edges.iter() {
+            let labels = [
+                (
+                    "conn_id",
+                    format!(
+                        "{:x}",
+                        fnv_hash(&format!(
+                            "{}{}{}{}",
+                            conn.client.name,
+                            conn.client.namespace,
+                            conn.server.name,
+                            conn.server.namespace
+                        ))
+                    ),
                 ),
-                client_id: format!(
-                    "{:x}",
-                    fnv_hash(&format!("{}{}", conn.client.name, conn.client.namespace))
+                (
+                    "client_id",
+                    format!(
+                        "{:x}",
+                        fnv_hash(&format!("{}{}", conn.client.name, conn.client.namespace))
+                    ),
                 ),
-                client_name: conn.client.name.clone(),
-                client_namespace: conn.client.namespace.clone(),
-                client_kind: conn.client.kind.clone(),
-                server_id: format!(
-                    "{:x}",
-                    fnv_hash(&format!("{}{}", conn.server.name, conn.server.namespace))
+                ("client_name", conn.client.name.clone()),
+                ("client_namespace", conn.client.namespace.clone()),
+                ("client_kind", conn.client.kind.clone()),
+                (
+                    "server_id",
+                    format!(
+                        "{:x}",
+                        fnv_hash(&format!("{}{}", conn.server.name, conn.server.namespace))
+                    ),
                 ),
-                server_name: conn.server.name.clone(),
-                server_namespace: conn.server.namespace.clone(),
-                server_kind: conn.server.kind.clone(),
-                server_port: conn.server_port.to_string(),
-                role: conn.role.to_string(),
-            };
-            conn_metric.get_or_create(&labels).set(*value as i64);
+                ("server_name", conn.server.name.clone()),
+                ("server_namespace", conn.server.namespace.clone()),
+                ("server_kind", conn.server.kind.clone()),
+                ("server_port", conn.server_port.to_string()),
+                (
+                    "server_port_name",
+                    conn.server_port_name
+                        .as_ref()
+                        .map(|port_name| port_name.name.clone())
+                        .unwrap_or_default(),
+                ),
+                (
+                    "server_app_protocol",
+                    conn.server_port_name
+                        .as_ref()
+                        .map(|port_name| port_name.app_protocol.clone())
+                        .unwrap_or_default(),
+                ),
+                ("role", conn.role.to_string()),
+            ];
+            sent_metric.samples.push(
+                Sample::new(labels.clone(), edge.totals.sent as f64).with_created(edge.created),
+            );
+            received_metric.samples.push(
+                Sample::new(labels.clone(), edge.totals.received as f64).with_created(edge.created),
+            );
+            failed_metric
+                .samples
+                .push(Sample::new(labels.clone(), edge.failed as f64).with_created(edge.created));
+            if edge.handshake.count > 0 {
+                handshake_metric.samples.push(
+                    Sample::histogram(labels.clone(), edge.handshake.clone())
+                        .with_created(edge.created),
+                );
+            }
+            retransmits_metric.samples.push(
+                Sample::new(labels.clone(), edge.totals.retransmits as f64)
+                    .with_created(edge.created),
+            );
+            lost_metric
+                .samples
+                .push(Sample::new(labels.clone(), edge.lost as f64));
+            if let Some(rtt) = edge.rtt.as_ref() {
+                rtt_min_metric
+                    .samples
+                    .push(Sample::new(labels.clone(), rtt.min));
+                rtt_avg_metric
+                    .samples
+                    .push(Sample::new(labels.clone(), rtt.sum / rtt.count as f64));
+                rtt_max_metric
+                    .samples
+                    .push(Sample::new(labels.clone(), rtt.max));
+            }
+            active_metric
+                .samples
+                .push(Sample::new(labels.clone(), edge.active as f64));
+            opened_metric
+                .samples
+                .push(Sample::new(labels.clone(), edge.opened as f64).with_created(edge.created));
+            closed_metric
+                .samples
+                .push(Sample::new(labels, edge.closed as f64).with_created(edge.created));
         }
 
-        let metric_encoder = encoder.encode_descriptor(
-            "connection_observed",
-            "total bytes_sent value of connections observed",
-            Some(&Unit::Bytes),
-            conn_metric.metric_type(),
-        )?;
-        conn_metric.encode(metric_encoder)?;
-
-        Ok(())
+        Ok(vec![
+            sent_metric,
+            received_metric,
+            active_metric,
+            opened_metric,
+            closed_metric,
+            failed_metric,
+            handshake_metric,
+            retransmits_metric,
+            lost_metric,
+            rtt_min_metric,
+            rtt_avg_metric,
+            rtt_max_metric,
+        ])
     }
 
     fn get_name(&self) -> String {
@@ -357,6 +824,46 @@ impl Program for ServiceMap {
         inner.metadata = metadata;
     }
 
+    fn decode_map_entry(
+        &self,
+        map_name: &str,
+        key: &[u8],
+        value: &[u8],
+    ) -> Option<(String, String)> {
+        if map_name != "CONNECTIONS" {
+            return None;
+        }
+        let key: ConnectionKey = read_pod(key)?;
+        let stats: ConnectionStats = read_pod(value)?;
+        let role = match key.role {
+            CONNECTION_ROLE_CLIENT => "client",
+            CONNECTION_ROLE_SERVER => "server",
+            _ => "unknown",
+        };
+        Some((
+            format!(
+                "id={} pid={} {} -> {} role={}",
+                key.id,
+                key.pid,
+                SocketAddr::new(Self::ip_addr(key.src_addr), key.src_port as u16),
+                SocketAddr::new(Self::ip_addr(key.dest_addr), key.dest_port as u16),
+                role
+            ),
+            format!(
+                "bytes_sent={} bytes_received={} is_active={} srtt_us={} total_retrans={} lost_out={} \
+                 handshake_ns={} connect_failed={}",
+                stats.bytes_sent,
+                stats.bytes_received,
+                stats.is_active,
+                stats.srtt_us >> 3,
+                stats.total_retrans,
+                stats.lost_out,
+                stats.handshake_ns,
+                stats.connect_failed
+            ),
+        ))
+    }
+
     fn get_program_info(&self) -> Result<ProgramInfo, Error> {
         let program_type: u32 = self.get_type().try_into()?;
         let state: u32 = self.get_state().clone().try_into()?;
@@ -371,17 +878,388 @@ impl Program for ServiceMap {
     }
 }
 
-#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
-struct Labels {
-    conn_id: String,
-    client_id: String,
-    client_name: String,
-    client_namespace: String,
-    client_kind: String,
-    server_id: String,
-    server_name: String,
-    server_namespace: String,
-    server_kind: String,
-    server_port: String,
-    role: String,
+#[cfg(test)]
+mod tests {
+    use conn_tracer_common::{AF_INET, AF_INET6};
+
+    use super::*;
+    use crate::managers::cache::ServiceBackend;
+
+    fn workload(name: &str) -> Arc<Workload> {
+        Arc::new(Workload {
+            name: name.to_string(),
+            namespace: "default".to_string(),
+            kind: "Deployment".to_string(),
+        })
+    }
+
+    fn connection(client: &str, server: &str) -> Connection {
+        Connection {
+            client: workload(client),
+            server: workload(server),
+            role: CONNECTION_ROLE_CLIENT,
+            server_port: 80,
+            server_port_name: None,
+        }
+    }
+
+    fn key(src: &str, dest: &str, role: u32) -> ConnectionKey {
+        let addr = |addr: &str| -> (u32, [u8; 16], u32) {
+            let addr: SocketAddr = addr.parse().unwrap();
+            let (family, octets) = match addr.ip() {
+                IpAddr::V4(ip) => (AF_INET as u32, ip.to_ipv6_mapped().octets()),
+                IpAddr::V6(ip) => (AF_INET6 as u32, ip.octets()),
+            };
+            (family, octets, addr.port() as u32)
+        };
+        let (family, src_addr, src_port) = addr(src);
+        let (_, dest_addr, dest_port) = addr(dest);
+        ConnectionKey {
+            id: 1,
+            pid: 1,
+            family,
+            src_addr,
+            src_port,
+            dest_addr,
+            dest_port,
+            role,
+        }
+    }
+
+    /// A cache where `frontend` calls the `api` Service, backed by the `api` workload.
+    fn cache() -> CacheManager {
+        let cache_mgr = CacheManager::empty();
+        {
+            let mut ips = cache_mgr.ip_to_workload.write();
+            ips.insert("10.0.0.5".parse().unwrap(), workload("frontend"));
+            ips.insert("10.0.0.1".parse().unwrap(), workload("api"));
+            ips.insert(
+                "10.96.0.10".parse().unwrap(),
+                Arc::new(Workload {
+                    name: "api".to_string(),
+                    namespace: "default".to_string(),
+                    kind: "Service".to_string(),
+                }),
+            );
+        }
+        cache_mgr.service_backends.write().insert(
+            ("10.96.0.10".parse().unwrap(), 80),
+            Arc::new(ServiceBackend {
+                addrs: vec!["10.0.0.1".parse().unwrap()],
+                port: 8080,
+            }),
+        );
+        cache_mgr
+    }
+
+    #[test]
+    fn test_join_backends() {
+        let service_map = ServiceMap::new();
+        let cache_mgr = cache();
+        let external = ExternalResolver::default();
+        let client = key("10.0.0.5:40000", "10.96.0.10:80", CONNECTION_ROLE_CLIENT);
+        let server = key("10.0.0.1:8080", "10.0.0.5:40000", CONNECTION_ROLE_SERVER);
+        let from_outside = key("10.0.0.1:8080", "203.0.113.7:40000", CONNECTION_ROLE_SERVER);
+
+        // Without the join, both sides are reported, the client one against the Service.
+        let conn = service_map
+            .build_connection(client, &cache_mgr, &external, false)
+            .unwrap();
+        assert_eq!(
+            (conn.server.kind.as_str(), conn.server_port),
+            ("Service", 80)
+        );
+        assert!(service_map
+            .build_connection(server, &cache_mgr, &external, false)
+            .is_ok());
+
+        // With the join, the client side is reported against the backend, and the server
+        // side only for clients outside the cluster.
+        let conn = service_map
+            .build_connection(client, &cache_mgr, &external, true)
+            .unwrap();
+        assert_eq!(
+            (conn.server.clone(), conn.server_port),
+            (workload("api"), 8080)
+        );
+        assert!(service_map
+            .build_connection(server, &cache_mgr, &external, true)
+            .is_err());
+        let conn = service_map
+            .build_connection(from_outside, &cache_mgr, &external, true)
+            .unwrap();
+        assert_eq!(conn.client.kind, "External");
+        assert_eq!(conn.server, workload("api"));
+    }
+
+    #[test]
+    fn test_idle_edges_expire() {
+        let mut inner = Inner::new();
+        inner.edge_ttl = Duration::from_secs(60);
+        let start = SystemTime::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let busy = connection("frontend", "backend");
+        let idle = connection("job", "backend");
+        let stats = |sent| EdgeStats {
+            totals: ConnectionTotals {
+                sent,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        inner.record_poll(
+            HashMap::from([(busy.clone(), stats(10)), (idle.clone(), stats(10))]),
+            at(0),
+        );
+        inner.past_conns_map.insert(idle.clone(), stats(10).totals);
+        // The idle edge keeps being reported with unchanged totals.
+        inner.record_poll(
+            HashMap::from([(busy.clone(), stats(20)), (idle.clone(), stats(10))]),
+            at(30),
+        );
+        assert_eq!(inner.edges.len(), 2);
+        inner.record_poll(
+            HashMap::from([(busy.clone(), stats(30)), (idle.clone(), stats(10))]),
+            at(90),
+        );
+        assert!(inner.edges.contains_key(&busy));
+        assert!(!inner.edges.contains_key(&idle));
+        assert!(inner.past_conns_map.is_empty());
+
+        // An edge seen again is a new series.
+        inner.record_poll(HashMap::from([(idle.clone(), stats(5))]), at(100));
+        assert_eq!(inner.edges[&idle].created, at(100));
+        assert_eq!(inner.edges[&idle].totals.sent, 5);
+    }
+
+    fn stats(is_active: u64, srtt_us: u64) -> ConnectionStats {
+        ConnectionStats {
+            bytes_sent: 100,
+            is_active,
+            srtt_us,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_ipv4_mapped_and_ipv6_keys() {
+        let service_map = ServiceMap::new();
+        let cache_mgr = cache();
+        cache_mgr
+            .ip_to_workload
+            .write()
+            .insert("fd00::1".parse().unwrap(), workload("api"));
+        let external = ExternalResolver::default();
+
+        // IPv4 peers of dual-stack sockets show up as IPv4-mapped addresses, and resolve
+        // like the IPv4 ones.
+        let v4 = key("10.0.0.5:40000", "10.0.0.1:80", CONNECTION_ROLE_CLIENT);
+        let mapped = key(
+            "[::ffff:10.0.0.5]:40000",
+            "[::ffff:10.0.0.1]:80",
+            CONNECTION_ROLE_CLIENT,
+        );
+        let v6 = key(
+            "[::ffff:10.0.0.5]:40000",
+            "[fd00::1]:80",
+            CONNECTION_ROLE_CLIENT,
+        );
+        assert_eq!(mapped.family, AF_INET6 as u32);
+        let expected = connection("frontend", "api");
+        for key in [v4, mapped, v6] {
+            let conn = service_map
+                .build_connection(key, &cache_mgr, &external, false)
+                .unwrap();
+            assert_eq!(conn, expected);
+        }
+        let unknown = key(
+            "[::ffff:10.0.0.5]:40000",
+            "[fd00::2]:80",
+            CONNECTION_ROLE_CLIENT,
+        );
+        let conn = service_map
+            .build_connection(unknown, &cache_mgr, &external, false)
+            .unwrap();
+        assert_eq!(conn.server.kind, "External");
+
+        // Loopback connections are left out in both forms.
+        let loopback = [
+            key("127.0.0.1:40000", "127.0.0.1:80", CONNECTION_ROLE_CLIENT),
+            key("[::1]:40000", "[::1]:80", CONNECTION_ROLE_CLIENT),
+            key(
+                "10.0.0.5:40000",
+                "[::ffff:127.0.0.1]:80",
+                CONNECTION_ROLE_CLIENT,
+            ),
+        ];
+        let polled = service_map.group_connections(
+            loopback.iter().map(|key| (*key, stats(1, 0))).collect(),
+            None,
+            &cache_mgr,
+            &external,
+            false,
+        );
+        assert!(polled.edges.is_empty());
+        assert_eq!(polled.open.len(), 3);
+    }
+
+    #[test]
+    fn test_resolve_port_name() {
+        let service_map = ServiceMap::new();
+        let cache_mgr = cache();
+        let port_name = |name: &str| {
+            Arc::new(PortName {
+                name: name.to_string(),
+                app_protocol: String::new(),
+            })
+        };
+        {
+            let mut port_names = cache_mgr.port_names.write();
+            port_names.insert(("10.96.0.10".parse().unwrap(), 80), port_name("http"));
+            port_names.insert(("10.0.0.1".parse().unwrap(), 8080), port_name("web"));
+        }
+        let external = ExternalResolver::default();
+
+        // Clients name the port of the address they connect to, servers their own port.
+        let client = key("10.0.0.5:40000", "10.96.0.10:80", CONNECTION_ROLE_CLIENT);
+        let server = key(
+            "[::ffff:10.0.0.1]:8080",
+            "10.0.0.5:40000",
+            CONNECTION_ROLE_SERVER,
+        );
+        let unnamed = key("10.0.0.5:40000", "10.0.0.1:9090", CONNECTION_ROLE_CLIENT);
+        for join_backends in [false, true] {
+            let conn = service_map
+                .build_connection(client, &cache_mgr, &external, join_backends)
+                .unwrap();
+            assert_eq!(conn.server_port_name, Some(port_name("http")));
+        }
+        let conn = service_map
+            .build_connection(server, &cache_mgr, &external, false)
+            .unwrap();
+        assert_eq!(conn.server_port_name, Some(port_name("web")));
+        let conn = service_map
+            .build_connection(unnamed, &cache_mgr, &external, false)
+            .unwrap();
+        assert_eq!(conn.server_port_name, None);
+    }
+
+    #[test]
+    fn test_connection_accounting() {
+        let service_map = ServiceMap::new();
+        let cache_mgr = cache();
+        let external = ExternalResolver::default();
+        let mut inner = Inner::new();
+        let edge = connection("frontend", "api");
+        let first = key("10.0.0.5:40000", "10.0.0.1:80", CONNECTION_ROLE_CLIENT);
+        let second = key("10.0.0.5:40001", "10.0.0.1:80", CONNECTION_ROLE_CLIENT);
+        let failed = key("10.0.0.5:40002", "10.0.0.1:80", CONNECTION_ROLE_CLIENT);
+        let existing = key("10.0.0.5:39999", "10.0.0.1:80", CONNECTION_ROLE_CLIENT);
+
+        // The first poll only learns of the connections that are already open.
+        let polled = service_map.group_connections(
+            vec![(
+                existing,
+                ConnectionStats {
+                    handshake_ns: 1_000_000,
+                    ..stats(1, 2000 << 3)
+                },
+            )],
+            inner.open_conns.as_ref(),
+            &cache_mgr,
+            &external,
+            false,
+        );
+        let stats_0 = &polled.edges[&edge];
+        assert_eq!((stats_0.active, stats_0.opened), (1, 0));
+        assert!(stats_0.handshakes.is_empty());
+        inner.open_conns = Some(polled.open);
+        inner.record_poll(polled.edges, SystemTime::now());
+
+        let polled = service_map.group_connections(
+            vec![
+                (existing, stats(1, 2000 << 3)),
+                (
+                    first,
+                    ConnectionStats {
+                        lost_out: 1,
+                        handshake_ns: 1_000_000,
+                        ..stats(1, 1000 << 3)
+                    },
+                ),
+                (second, stats(1, 3000 << 3)),
+                (
+                    failed,
+                    ConnectionStats {
+                        connect_failed: 1,
+                        ..stats(0, 0)
+                    },
+                ),
+            ],
+            inner.open_conns.as_ref(),
+            &cache_mgr,
+            &external,
+            false,
+        );
+        assert_eq!(polled.open, HashSet::from([existing, first, second]));
+        assert_eq!(polled.closed, vec![failed]);
+        let stats_1 = &polled.edges[&edge];
+        assert_eq!(
+            (
+                stats_1.active,
+                stats_1.opened,
+                stats_1.closed,
+                stats_1.failed
+            ),
+            (3, 2, 0, 1)
+        );
+        assert_eq!(stats_1.totals.sent, 400);
+        assert_eq!(stats_1.lost, 1);
+        assert_eq!(stats_1.handshakes, vec![0.001]);
+        let rtt = stats_1.rtt.unwrap();
+        assert_eq!((rtt.min, rtt.max, rtt.count), (0.001, 0.003, 3));
+        assert!((rtt.sum / rtt.count as f64 - 0.002).abs() < 1e-9);
+        inner.open_conns = Some(polled.open);
+        inner.record_poll(polled.edges, SystemTime::now());
+
+        // The first connection closes: it is not opened again, and is left out of the RTT.
+        let polled = service_map.group_connections(
+            vec![
+                (existing, stats(1, 2000 << 3)),
+                (first, stats(0, 1000 << 3)),
+                (second, stats(1, 3000 << 3)),
+            ],
+            inner.open_conns.as_ref(),
+            &cache_mgr,
+            &external,
+            false,
+        );
+        assert_eq!(polled.closed, vec![first]);
+        let stats_2 = &polled.edges[&edge];
+        assert_eq!(
+            (
+                stats_2.active,
+                stats_2.opened,
+                stats_2.closed,
+                stats_2.failed
+            ),
+            (2, 0, 1, 0)
+        );
+        assert!(stats_2.handshakes.is_empty());
+        inner.record_poll(polled.edges, SystemTime::now());
+
+        let counters = &inner.edges[&edge];
+        assert_eq!(
+            (
+                counters.active,
+                counters.opened,
+                counters.closed,
+                counters.failed
+            ),
+            (2, 2, 1, 1)
+        );
+        let rtt = counters.rtt.unwrap();
+        assert_eq!((rtt.min, rtt.max, rtt.count), (0.002, 0.003, 2));
+    }
 }
@@ -2,12 +2,12 @@ use std::collections::HashMap;
 use std::fmt::Debug;
 
 use async_trait::async_trait;
-use prometheus_client::encoding::DescriptorEncoder;
 use tokio::sync::broadcast::Receiver;
 
 use agent_api::v1::ProgramInfo;
 
 use crate::managers::cache::CacheManager;
+use crate::managers::metrics::{MetricFamily, MetricsManager};
 use agent_api::{ProgramState, ProgramType};
 
 #[derive(Debug, Clone)]
@@ -22,12 +22,15 @@ pub trait Program: Debug + Send + Sync + 'static {
         &self,
         metadata: HashMap<String, String>,
         cache_manager: CacheManager,
+        metrics_manager: MetricsManager,
         maps: HashMap<String, u32>,
     ) -> Result<(), anyhow::Error>;
     async fn start(&self, shutdown_rx: Receiver<ShutdownSignal>) -> Result<(), anyhow::Error>;
 
     async fn stop(&self) -> Result<(), anyhow::Error>;
-    fn collect(&self, encoder: &mut DescriptorEncoder) -> Result<(), anyhow::Error>;
+    /// Collects the current metrics of the program. Called by the program on its own
+    /// poll interval through [`MetricsManager::collect`], never by a scrape.
+    fn collect(&self) -> Result<Vec<MetricFamily>, anyhow::Error>;
     fn get_name(&self) -> String;
     fn get_state(&self) -> ProgramState;
     fn set_state(&self, state: ProgramState);
@@ -35,4 +38,15 @@ pub trait Program: Debug + Send + Sync + 'static {
     fn get_metadata(&self) -> HashMap<String, String>;
     fn set_metadata(&self, metadata: HashMap<String, String>);
     fn get_program_info(&self) -> Result<ProgramInfo, anyhow::Error>;
+
+    /// Decodes a raw entry of one of the program's eBPF maps into a readable key and value.
+    /// Returns `None` if the program does not know the layout of the map.
+    fn decode_map_entry(
+        &self,
+        _map_name: &str,
+        _key: &[u8],
+        _value: &[u8],
+    ) -> Option<(String, String)> {
+        None
+    }
 }
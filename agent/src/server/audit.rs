@@ -0,0 +1,231 @@
+use std::fs::{create_dir_all, metadata, read_to_string, rename, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::{SecondsFormat, Utc};
+use log::error;
+use parking_lot::Mutex;
+use serde_json::{json, Value};
+use tonic::{Request, Status};
+
+use agent_api::v1::bytecode_location::Location;
+use agent_api::v1::{
+    BytecodeImage, BytecodeLocation, LoadRequest, PullBytecodeRequest, UnloadRequest,
+};
+
+use crate::server::auth::Peer;
+use crate::server::rpc::cert_sans;
+
+const REDACTED: &str = "<redacted>";
+
+/// Append-only log of mutating agent RPCs, written as JSON lines.
+///
+/// The log is rotated once it grows beyond `max_size` bytes, keeping up to `max_files`
+/// rotated files next to it (`audit.log.1` being the most recent).
+#[derive(Debug, Clone)]
+pub(crate) struct AuditLog {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: Arc<Mutex<File>>,
+}
+
+fn open_append(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl AuditLog {
+    pub(crate) fn new(path: PathBuf, max_size: u64, max_files: usize) -> anyhow::Result<Self> {
+        if let Some(parent) = path.parent() {
+            create_dir_all(parent)?;
+        }
+        let file = open_append(&path)?;
+        Ok(Self {
+            path,
+            max_size,
+            max_files,
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&self, file: &mut File) -> std::io::Result<()> {
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        if self.max_files > 0 {
+            rename(&self.path, self.rotated_path(1))?;
+        }
+        *file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&self.path)?;
+        Ok(())
+    }
+
+    fn write(&self, entry: &Value) -> std::io::Result<()> {
+        let mut file = self.file.lock();
+        if metadata(&self.path).map(|m| m.len()).unwrap_or(0) >= self.max_size {
+            self.rotate(&mut file)?;
+        }
+        writeln!(file, "{}", entry)?;
+        file.flush()
+    }
+
+    /// Records the call of `method` by `peer` with the given (already redacted)
+    /// parameters, along with its outcome.
+    pub(crate) fn record<T>(
+        &self,
+        method: &str,
+        peer: Value,
+        params: Value,
+        result: &Result<T, Status>,
+    ) {
+        let outcome = match result {
+            Ok(_) => json!({ "status": "ok" }),
+            Err(status) => json!({
+                "status": "error",
+                "code": format!("{:?}", status.code()),
+                "message": status.message(),
+            }),
+        };
+        let entry = json!({
+            "timestamp": Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+            "method": method,
+            "peer": peer,
+            "request": params,
+            "outcome": outcome,
+        });
+        if let Err(e) = self.write(&entry) {
+            error!("Failed to write audit log entry {}: {:?}", entry, e);
+        }
+    }
+
+    /// Returns up to `limit` of the most recent entries of the current log file.
+    pub(crate) fn tail(&self, limit: usize) -> std::io::Result<Vec<String>> {
+        let _guard = self.file.lock();
+        let content = read_to_string(&self.path)?;
+        let lines: Vec<&str> = content.lines().collect();
+        Ok(lines[lines.len().saturating_sub(limit)..]
+            .iter()
+            .map(|l| l.to_string())
+            .collect())
+    }
+}
+
+/// Describes the sender of `request`: its credentials on the unix socket, or its
+/// address and client certificate names on the TCP listener.
+pub(crate) fn peer<T>(request: &Request<T>) -> Value {
+    if let Some(peer) = Peer::from_request(request) {
+        return json!({
+            "transport": "unix",
+            "uid": peer.uid,
+            "gid": peer.gid,
+            "pid": peer.pid,
+        });
+    }
+    let sans = request
+        .peer_certs()
+        .and_then(|certs| certs.first().map(|cert| cert_sans(cert.get_ref())))
+        .unwrap_or_default();
+    json!({
+        "transport": "tcp",
+        "addr": request.remote_addr().map(|addr| addr.to_string()),
+        "sans": sans,
+    })
+}
+
+/// Describes a bytecode image without its registry password.
+pub(crate) fn redact_image(image: &BytecodeImage) -> Value {
+    json!({
+        "url": image.url,
+        "image_pull_policy": image.image_pull_policy,
+        "username": image.username,
+        "password": image.password.as_ref().map(|_| REDACTED),
+    })
+}
+
+fn redact_location(location: Option<&BytecodeLocation>) -> Value {
+    match location.and_then(|l| l.location.as_ref()) {
+        Some(Location::Image(image)) => json!({ "image": redact_image(image) }),
+        Some(Location::File(file)) => json!({ "file": file }),
+        None => Value::Null,
+    }
+}
+
+pub(crate) fn load_params(request: &LoadRequest) -> Value {
+    json!({
+        "name": request.name,
+        "program_type": request.program_type,
+        "bytecode": redact_location(request.bytecode.as_ref()),
+        "ebpf_maps": request.ebpf_maps,
+        "metadata": request.metadata,
+    })
+}
+
+pub(crate) fn unload_params(request: &UnloadRequest) -> Value {
+    json!({ "name": request.name })
+}
+
+pub(crate) fn pull_bytecode_params(request: &PullBytecodeRequest) -> Value {
+    json!({ "image": request.image.as_ref().map(redact_image) })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs::remove_dir_all;
+
+    use super::*;
+
+    #[test]
+    fn test_audit_log_rotates_and_redacts() {
+        let dir = temp_dir().join(format!("eva-audit-{}", std::process::id()));
+        let path = dir.join("audit.log");
+        let audit_log = AuditLog::new(path.clone(), 1, 2).unwrap();
+
+        let image = BytecodeImage {
+            url: "quay.io/eva/prog:latest".to_string(),
+            image_pull_policy: 0,
+            username: Some("user".to_string()),
+            password: Some("secret".to_string()),
+        };
+        for name in ["a", "b", "c"] {
+            audit_log.record::<()>(
+                "Load",
+                json!({ "transport": "unix", "uid": 0 }),
+                load_params(&LoadRequest {
+                    name: name.to_string(),
+                    bytecode: Some(BytecodeLocation {
+                        location: Some(Location::Image(image.clone())),
+                    }),
+                    ..Default::default()
+                }),
+                &Err(Status::not_found("missing")),
+            );
+        }
+
+        let entries = audit_log.tail(10).unwrap();
+        assert_eq!(entries.len(), 1);
+        let entry: Value = serde_json::from_str(&entries[0]).unwrap();
+        assert_eq!(entry["request"]["name"], "c");
+        assert_eq!(entry["request"]["bytecode"]["image"]["password"], REDACTED);
+        assert_eq!(entry["outcome"]["code"], "NotFound");
+        assert!(!entries[0].contains("secret"));
+        assert!(audit_log.rotated_path(1).exists());
+        assert!(audit_log.rotated_path(2).exists());
+        assert!(!audit_log.rotated_path(3).exists());
+
+        remove_dir_all(dir).unwrap();
+    }
+}
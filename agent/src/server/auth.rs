@@ -0,0 +1,225 @@
+use std::fs::read_to_string;
+use std::path::Path;
+
+use serde::Deserialize;
+use tokio::net::unix::UCred;
+use tonic::transport::server::UdsConnectInfo;
+use tonic::{Request, Status};
+
+/// The credentials of a process connected to the agent unix socket.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Peer {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+    pub cgroup: Option<String>,
+}
+
+impl Peer {
+    pub(crate) fn from_ucred(cred: &UCred) -> Self {
+        let pid = cred.pid();
+        Self {
+            uid: cred.uid(),
+            gid: cred.gid(),
+            pid,
+            cgroup: pid.and_then(|pid| read_to_string(format!("/proc/{}/cgroup", pid)).ok()),
+        }
+    }
+
+    /// Returns the credentials of the peer that sent `request`, if it came in over the
+    /// unix socket.
+    pub(crate) fn from_request<T>(request: &Request<T>) -> Option<Self> {
+        request
+            .extensions()
+            .get::<UdsConnectInfo>()
+            .and_then(|info| info.peer_cred.as_ref())
+            .map(Self::from_ucred)
+    }
+}
+
+/// A rule granting access to a set of RPCs. All criteria that are set must match.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Rule {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    /// Matches if this is a segment of a cgroup path in the peer's `/proc/<pid>/cgroup`,
+    /// or the pod UID or container ID of one, as returned by [`cgroup_ids`]. Requires the
+    /// agent to share the host PID namespace.
+    pub cgroup: Option<String>,
+    /// RPC names such as `List` or `Load`, or `*` for all of them.
+    pub methods: Vec<String>,
+}
+
+impl Rule {
+    fn matches(&self, peer: &Peer) -> bool {
+        if self.uid.is_some_and(|uid| uid != peer.uid) {
+            return false;
+        }
+        if self.gid.is_some_and(|gid| gid != peer.gid) {
+            return false;
+        }
+        if let Some(cgroup) = self.cgroup.as_ref() {
+            match peer.cgroup.as_ref() {
+                Some(c) if cgroup_ids(c).any(|id| &id == cgroup) => {}
+                _ => return false,
+            }
+        }
+        true
+    }
+
+    fn allows(&self, method: &str) -> bool {
+        self.methods.iter().any(|m| m == "*" || m == method)
+    }
+}
+
+/// Returns the identifiers of a process in the content of its `/proc/<pid>/cgroup`: the
+/// segments of its cgroup paths, and the pod UIDs and container IDs they name with either
+/// the cgroupfs or the systemd cgroup driver, e.g. `pod<uid>` or
+/// `kubepods-burstable-pod<uid>.slice`, and `<id>` or `cri-containerd-<id>.scope`.
+fn cgroup_ids(content: &str) -> impl Iterator<Item = String> + '_ {
+    content
+        .lines()
+        .filter_map(|line| line.splitn(3, ':').nth(2))
+        .flat_map(|path| path.split('/'))
+        .filter(|segment| !segment.is_empty())
+        .flat_map(|segment| {
+            let name = segment
+                .strip_suffix(".slice")
+                .or_else(|| segment.strip_suffix(".scope"))
+                .unwrap_or(segment);
+            let last = name.rsplit('-').next().unwrap_or(name);
+            let pod_uid = name
+                .strip_prefix("pod")
+                .map(str::to_string)
+                .or_else(|| last.strip_prefix("pod").map(|uid| uid.replace('_', "-")))
+                .filter(|uid| is_pod_uid(uid));
+            let container_id = Some(last.to_string()).filter(|id| is_container_id(id));
+            [Some(segment.to_string()), pod_uid, container_id]
+                .into_iter()
+                .flatten()
+        })
+}
+
+fn is_pod_uid(s: &str) -> bool {
+    s.len() == 36 && s.chars().all(|c| c.is_ascii_hexdigit() || c == '-')
+}
+
+fn is_container_id(s: &str) -> bool {
+    s.len() == 64 && s.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Authorization policy for the agent unix socket. Rules are evaluated in order and the
+/// first rule matching the peer decides; peers matching no rule are denied.
+#[derive(Debug, Clone, Deserialize)]
+pub(crate) struct Policy {
+    pub rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub(crate) fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = read_to_string(path)?;
+        Ok(toml::from_str(&content)?)
+    }
+
+    pub(crate) fn is_allowed(&self, peer: &Peer, method: &str) -> bool {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(peer))
+            .is_some_and(|rule| rule.allows(method))
+    }
+
+    /// Checks whether the sender of `request` may call `method`. Requests that did not
+    /// come in over the unix socket are authenticated by mutual TLS and not checked here.
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn authorize<T>(&self, request: &Request<T>, method: &str) -> Result<(), Status> {
+        if request.extensions().get::<UdsConnectInfo>().is_none() {
+            return Ok(());
+        }
+        let peer = Peer::from_request(request)
+            .ok_or_else(|| Status::permission_denied("Unable to determine peer credentials"))?;
+        if self.is_allowed(&peer, method) {
+            Ok(())
+        } else {
+            Err(Status::permission_denied(format!(
+                "Peer uid={} gid={} is not allowed to call {}",
+                peer.uid, peer.gid, method
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(uid: u32, gid: u32, cgroup: Option<&str>) -> Peer {
+        Peer {
+            uid,
+            gid,
+            pid: None,
+            cgroup: cgroup.map(|c| c.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_policy_first_matching_rule_decides() {
+        let policy: Policy = toml::from_str(
+            r#"
+            [[rules]]
+            uid = 0
+            methods = ["*"]
+
+            [[rules]]
+            gid = 2000
+            methods = ["List", "Get"]
+
+            [[rules]]
+            cgroup = "pod-monitoring"
+            methods = ["List"]
+            "#,
+        )
+        .unwrap();
+
+        assert!(policy.is_allowed(&peer(0, 0, None), "Load"));
+        assert!(policy.is_allowed(&peer(1000, 2000, None), "Get"));
+        assert!(!policy.is_allowed(&peer(1000, 2000, None), "Unload"));
+        assert!(policy.is_allowed(
+            &peer(1000, 1000, Some("0::/kubepods/pod-monitoring/abc")),
+            "List"
+        ));
+        assert!(!policy.is_allowed(
+            &peer(1000, 1000, Some("0::/kubepods/pod-monitoring/abc")),
+            "Get"
+        ));
+        assert!(!policy.is_allowed(&peer(1000, 1000, None), "List"));
+    }
+
+    #[test]
+    fn test_cgroup_rule_matches_whole_ids() {
+        let uid = "0b7c6a1e-3f2d-4c5b-9a8e-7d6c5b4a3f2e";
+        let container = "4f1d2c3b".repeat(8);
+        let systemd = format!(
+            "0::/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod{}.slice/cri-containerd-{}.scope",
+            uid.replace('-', "_"),
+            container
+        );
+        let cgroupfs = format!("0::/kubepods/besteffort/pod{}/{}", uid, container);
+        let rule = |cgroup: &str| Rule {
+            uid: None,
+            gid: None,
+            cgroup: Some(cgroup.to_string()),
+            methods: vec!["*".to_string()],
+        };
+
+        for cgroup in [&systemd, &cgroupfs] {
+            let peer = peer(1000, 1000, Some(cgroup));
+            assert!(rule(uid).matches(&peer));
+            assert!(rule(&container).matches(&peer));
+            // Partial IDs and parts of segments match other pods too, so they are denied.
+            assert!(!rule(&uid[..8]).matches(&peer));
+            assert!(!rule(&container[..12]).matches(&peer));
+            assert!(!rule("kubepods-burst").matches(&peer));
+            assert!(!rule("pod").matches(&peer));
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bpfman_api::v1::bpfman_client::BpfmanClient;
+use log::{error, info, warn};
+use tokio::pin;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tonic::server::NamedService;
+use tonic::transport::Channel;
+use tonic::Request;
+use tonic_health::pb::health_server::{Health, HealthServer};
+use tonic_health::server::{health_reporter, HealthReporter};
+use tonic_health::ServingStatus;
+use tonic_reflection::server::{ServerReflection, ServerReflectionServer};
+
+use agent_api::v1::agent_server::AgentServer;
+
+use crate::collector::agent::AGENT_METRICS;
+use crate::common::constants::{HEALTH_CHECK_INTERVAL, HEALTH_CHECK_TIMEOUT};
+use crate::managers::cache::CacheManager;
+use crate::progs::types::ShutdownSignal;
+use crate::server::rpc::AgentService;
+
+/// Whether the agent is ready to serve requests: the Kubernetes cache has synced and
+/// bpfman is reachable.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Readiness {
+    cache_synced: Arc<AtomicBool>,
+    bpfman_reachable: Arc<AtomicBool>,
+}
+
+impl Readiness {
+    pub(crate) fn cache_synced(&self) -> bool {
+        self.cache_synced.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn bpfman_reachable(&self) -> bool {
+        self.bpfman_reachable.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn is_ready(&self) -> bool {
+        self.cache_synced() && self.bpfman_reachable()
+    }
+}
+
+/// Serves `grpc.health.v1.Health` for the whole server (`""`) and the agent service.
+#[derive(Debug, Clone)]
+pub(crate) struct HealthChecker {
+    readiness: Readiness,
+    reporter: HealthReporter,
+    cache_manager: CacheManager,
+    bpf_client: BpfmanClient<Channel>,
+}
+
+impl HealthChecker {
+    /// Creates the checker together with the health service, which reports NOT_SERVING
+    /// until [`HealthChecker::start`] finds the agent ready.
+    pub(crate) async fn new(
+        cache_manager: CacheManager,
+        bpf_client: BpfmanClient<Channel>,
+    ) -> (Self, HealthServer<impl Health>) {
+        let (reporter, health_service) = health_reporter();
+        let mut checker = Self {
+            readiness: Readiness::default(),
+            reporter,
+            cache_manager,
+            bpf_client,
+        };
+        checker.report(ServingStatus::NotServing).await;
+        (checker, health_service)
+    }
+
+    pub(crate) fn readiness(&self) -> Readiness {
+        self.readiness.clone()
+    }
+
+    async fn report(&mut self, status: ServingStatus) {
+        self.reporter.set_service_status("", status).await;
+        self.reporter
+            .set_service_status(<AgentServer<AgentService> as NamedService>::NAME, status)
+            .await;
+    }
+
+    async fn probe_bpfman(&self) -> bool {
+        let request = Request::new(bpfman_api::v1::ListRequest {
+            program_type: None,
+            bpfman_programs_only: Some(true),
+            match_metadata: Default::default(),
+        });
+        let mut bpf_client = self.bpf_client.clone();
+        let timeout = Duration::from_secs(HEALTH_CHECK_TIMEOUT);
+        match tokio::time::timeout(timeout, bpf_client.list(request)).await {
+            Ok(Ok(_)) => true,
+            Ok(Err(e)) => {
+                AGENT_METRICS.record_bpfman_error("List");
+                warn!("bpfman health check failed: {}", e.message());
+                false
+            }
+            Err(_) => {
+                AGENT_METRICS.record_bpfman_error("List");
+                warn!("bpfman health check timed out after {:?}", timeout);
+                false
+            }
+        }
+    }
+
+    /// Waits for the cache to sync, then keeps probing bpfman and updates the reported
+    /// status whenever readiness changes.
+    pub(crate) async fn start(mut self, mut shutdown_rx: broadcast::Receiver<ShutdownSignal>) {
+        let cache_manager = self.cache_manager.clone();
+        let cache_sync = cache_manager.wait_for_cache_sync();
+        pin!(cache_sync);
+        loop {
+            tokio::select! {
+                res = &mut cache_sync => {
+                    if let Err(e) = res {
+                        error!("Failed to sync cache: {:?}", e);
+                        return;
+                    }
+                    self.readiness.cache_synced.store(true, Ordering::Relaxed);
+                    break;
+                }
+                signal = shutdown_rx.recv() => {
+                    if is_shutdown(signal) {
+                        return;
+                    }
+                }
+            }
+        }
+
+        let mut interval = tokio::time::interval(Duration::from_secs(HEALTH_CHECK_INTERVAL));
+        loop {
+            tokio::select! {
+                signal = shutdown_rx.recv() => {
+                    if is_shutdown(signal) {
+                        return;
+                    }
+                }
+                _ = interval.tick() => {
+                    let reachable = self.probe_bpfman().await;
+                    let was_ready = self.readiness.is_ready();
+                    self.readiness.bpfman_reachable.store(reachable, Ordering::Relaxed);
+                    if self.readiness.is_ready() != was_ready {
+                        info!("Agent readiness changed to {}", !was_ready);
+                        let status = if was_ready {
+                            ServingStatus::NotServing
+                        } else {
+                            ServingStatus::Serving
+                        };
+                        self.report(status).await;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn is_shutdown(signal: Result<ShutdownSignal, RecvError>) -> bool {
+    matches!(signal, Ok(ShutdownSignal::All) | Err(RecvError::Closed))
+}
+
+/// Builds the gRPC server reflection service for the agent and health APIs.
+pub(crate) fn reflection_service() -> anyhow::Result<ServerReflectionServer<impl ServerReflection>>
+{
+    Ok(tonic_reflection::server::Builder::configure()
+        .register_encoded_file_descriptor_set(agent_api::FILE_DESCRIPTOR_SET)
+        .register_encoded_file_descriptor_set(tonic_health::pb::FILE_DESCRIPTOR_SET)
+        .build()?)
+}
@@ -1,3 +1,4 @@
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::net::SocketAddr;
 use std::sync::Arc;
@@ -11,26 +12,111 @@ use hyper::{Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
 use log::{debug, info};
 use prometheus_client::{encoding::text::encode, registry::Registry};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
 use tokio::pin;
 use tokio::sync::broadcast::Receiver;
 use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
 
-use crate::collector::Collector;
+use agent_api::ProgramState;
+
+use crate::collector::{Collector, FamiliesCollector};
+use crate::common::types::ListFilter;
+use crate::managers::metrics::MetricsManager;
 use crate::managers::registry::RegistryManager;
 use crate::progs::types::ShutdownSignal;
+use crate::server::health::Readiness;
+use crate::server::http_auth::{MetricsAuth, MetricsTlsConfig};
+
+/// Routes requests of the metrics server to the metrics, liveness and readiness
+/// endpoints.
+#[derive(Debug)]
+pub(crate) struct Router {
+    registry: Registry,
+    metrics_manager: MetricsManager,
+    metrics_path: String,
+    readiness: Readiness,
+    registry_manager: RegistryManager,
+    tolerated_failures: Vec<String>,
+    auth: MetricsAuth,
+}
+
+impl Router {
+    pub(crate) fn new(
+        metrics_path: String,
+        registry_manager: RegistryManager,
+        metrics_manager: MetricsManager,
+        collector: Collector,
+        readiness: Readiness,
+        tolerated_failures: Vec<String>,
+        auth: MetricsAuth,
+    ) -> Self {
+        let mut registry = Registry::default();
+        registry.register_collector(Box::new(collector));
+        Self {
+            registry,
+            metrics_manager,
+            metrics_path,
+            readiness,
+            registry_manager,
+            tolerated_failures,
+            auth,
+        }
+    }
+
+    /// Returns the outcome of every readiness check, in the format of the Kubernetes
+    /// `/readyz?verbose` endpoints.
+    fn readiness_checks(&self) -> (bool, String) {
+        let failed_programs: Vec<String> = self
+            .registry_manager
+            .list_programs(ListFilter::new(None, HashMap::new()))
+            .iter()
+            .filter(|prog| prog.get_state() == ProgramState::Failed)
+            .map(|prog| prog.get_name())
+            .filter(|name| !self.tolerated_failures.contains(name))
+            .collect();
+        let checks = [
+            ("cache-sync", self.readiness.cache_synced(), String::new()),
+            ("bpfman", self.readiness.bpfman_reachable(), String::new()),
+            (
+                "programs",
+                failed_programs.is_empty(),
+                format!(" failed: {}", failed_programs.join(",")),
+            ),
+        ];
+
+        let mut body = String::new();
+        for (name, ok, reason) in checks.iter() {
+            if *ok {
+                body.push_str(&format!("[+]{} ok\n", name));
+            } else {
+                body.push_str(&format!("[-]{} failed{}\n", name, reason));
+            }
+        }
+        let ready = checks.iter().all(|(_, ok, _)| *ok);
+        body.push_str(if ready {
+            "readyz check passed\n"
+        } else {
+            "readyz check failed\n"
+        });
+        (ready, body)
+    }
+}
 
 pub async fn serve(
     address: String,
-    registry_manager: RegistryManager,
+    router: Router,
+    tls_config: Option<MetricsTlsConfig>,
     shutdown_rx: Receiver<ShutdownSignal>,
 ) -> anyhow::Result<JoinHandle<()>> {
     let metrics_addr = address.parse::<SocketAddr>()?;
-    let collector = Box::new(Collector::new(registry_manager));
-    let mut registry = Registry::default();
-    registry.register_collector(collector);
+    let tls_acceptor = match tls_config {
+        Some(tls_config) => Some(tls_config.acceptor()?),
+        None => None,
+    };
     let server_handle = tokio::spawn(async move {
-        start_metrics_server(metrics_addr, registry, shutdown_rx)
+        start_metrics_server(metrics_addr, router, tls_acceptor, shutdown_rx)
             .await
             .unwrap();
     });
@@ -40,11 +126,12 @@ pub async fn serve(
 /// Start an HTTP server to report metrics.
 async fn start_metrics_server(
     addr: SocketAddr,
-    registry: Registry,
+    router: Router,
+    tls_acceptor: Option<TlsAcceptor>,
     mut shutdown_rx: Receiver<ShutdownSignal>,
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let listener = TcpListener::bind(&addr).await?;
-    let registry = Arc::new(registry);
+    let router = Arc::new(router);
     let connection_timeouts = vec![Duration::from_secs(5), Duration::from_secs(2)];
 
     loop {
@@ -60,28 +147,29 @@ async fn start_metrics_server(
             },
             accept_result = listener.accept() => {
                 let (stream, _) = accept_result?;
-                let io = TokioIo::new(stream);
-                let registry = registry.clone();
+                let router = router.clone();
+                let tls_acceptor = tls_acceptor.clone();
                 let connection_timeouts_clone = connection_timeouts.clone();
 
                 tokio::task::spawn(async move {
-                    let conn = http1::Builder::new().serve_connection(io, service_fn(move |req| request_handler(registry.clone(), req)));
-                    pin!(conn);
-
-                    for sleep_duration in connection_timeouts_clone {
-                        tokio::select! {
-                            res = conn.as_mut() => {
-                                match res {
-                                    Ok(()) => debug!("Connection completed without error"),
-                                    Err(e) => debug!("Error serving connection: {:?}", e),
-                                };
-                                break;
-                            }
-                            _ = tokio::time::sleep(sleep_duration) => {
-                                debug!("Timeout after {:?}, calling graceful_shutdown", sleep_duration);
-                                conn.as_mut().graceful_shutdown();
-                            }
+                    let acceptor = match tls_acceptor {
+                        Some(acceptor) => acceptor,
+                        None => {
+                            serve_connection(stream, router, false, connection_timeouts_clone).await;
+                            return;
+                        }
+                    };
+                    match tokio::time::timeout(connection_timeouts_clone[0], acceptor.accept(stream)).await {
+                        Ok(Ok(stream)) => {
+                            let client_verified = stream
+                                .get_ref()
+                                .1
+                                .peer_certificates()
+                                .is_some_and(|certs| !certs.is_empty());
+                            serve_connection(stream, router, client_verified, connection_timeouts_clone).await;
                         }
+                        Ok(Err(e)) => debug!("TLS handshake failed: {:?}", e),
+                        Err(_) => debug!("TLS handshake timed out"),
                     }
                 });
             }
@@ -91,13 +179,109 @@ async fn start_metrics_server(
     Ok(())
 }
 
+async fn serve_connection<S>(
+    stream: S,
+    router: Arc<Router>,
+    client_verified: bool,
+    connection_timeouts: Vec<Duration>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let io = TokioIo::new(stream);
+    let conn = http1::Builder::new().serve_connection(
+        io,
+        service_fn(move |req| request_handler(router.clone(), client_verified, req)),
+    );
+    pin!(conn);
+
+    for sleep_duration in connection_timeouts {
+        tokio::select! {
+            res = conn.as_mut() => {
+                match res {
+                    Ok(()) => debug!("Connection completed without error"),
+                    Err(e) => debug!("Error serving connection: {:?}", e),
+                };
+                break;
+            }
+            _ = tokio::time::sleep(sleep_duration) => {
+                debug!("Timeout after {:?}, calling graceful_shutdown", sleep_duration);
+                conn.as_mut().graceful_shutdown();
+            }
+        }
+    }
+}
+
+fn text_response(status: StatusCode, body: String) -> Response<Full<Bytes>> {
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Full::from(body))
+        .unwrap()
+}
+
 async fn request_handler(
-    registry: Arc<Registry>,
-    _request: Request<hyper::body::Incoming>,
+    router: Arc<Router>,
+    client_verified: bool,
+    request: Request<hyper::body::Incoming>,
 ) -> Result<Response<Full<Bytes>>, Infallible> {
-    let reg = registry.clone();
+    let path = request.uri().path();
+    let program = path
+        .strip_prefix(router.metrics_path.as_str())
+        .and_then(|rest| rest.strip_prefix('/'))
+        .filter(|program| !program.is_empty() && !program.contains('/'));
+    match path {
+        path if path == router.metrics_path || program.is_some() => {
+            let authorization = request
+                .headers()
+                .get(hyper::header::AUTHORIZATION)
+                .and_then(|value| value.to_str().ok());
+            if let Err(status) = router
+                .auth
+                .check(authorization, client_verified, path)
+                .await
+            {
+                return Ok(text_response(
+                    status,
+                    format!("{}\n", status.canonical_reason().unwrap_or_default()),
+                ));
+            }
+            match program {
+                Some(program) => match router.metrics_manager.snapshot(program) {
+                    Some(families) => {
+                        let mut registry = Registry::default();
+                        registry.register_collector(Box::new(FamiliesCollector(
+                            families.as_ref().clone(),
+                        )));
+                        metrics_handler(&registry)
+                    }
+                    None => Ok(text_response(
+                        StatusCode::NOT_FOUND,
+                        format!("no metrics for program {}\n", program),
+                    )),
+                },
+                None => metrics_handler(&router.registry),
+            }
+        }
+        "/healthz" => Ok(text_response(StatusCode::OK, "ok\n".to_string())),
+        "/readyz" => {
+            let (ready, body) = router.readiness_checks();
+            let status = if ready {
+                StatusCode::OK
+            } else {
+                StatusCode::SERVICE_UNAVAILABLE
+            };
+            Ok(text_response(status, body))
+        }
+        _ => Ok(text_response(
+            StatusCode::NOT_FOUND,
+            "404 page not found\n".to_string(),
+        )),
+    }
+}
+
+fn metrics_handler(registry: &Registry) -> Result<Response<Full<Bytes>>, Infallible> {
     let mut buf = String::new();
-    match encode(&mut buf, &reg.clone()) {
+    match encode(&mut buf, registry) {
         Ok(_) => Ok(Response::builder()
             .header(
                 hyper::header::CONTENT_TYPE,
@@ -182,8 +366,17 @@ mod tests {
 
         let (_, shutdown_rx) = tokio::sync::broadcast::channel(1);
 
+        let router = Router {
+            registry,
+            metrics_manager: MetricsManager::default(),
+            metrics_path: "/metrics".to_string(),
+            readiness: Readiness::default(),
+            registry_manager: RegistryManager::new(),
+            tolerated_failures: vec![],
+            auth: MetricsAuth::default(),
+        };
         let server_handle = tokio::spawn(async move {
-            start_metrics_server(metrics_addr, registry, shutdown_rx)
+            start_metrics_server(metrics_addr, router, None, shutdown_rx)
                 .await
                 .unwrap();
         });
@@ -213,6 +406,17 @@ mod tests {
         body_reader.read_to_string(&mut body_string).unwrap();
 
         assert_eq!(body_string, "# HELP http_requests Number of HTTP requests received.\n# TYPE http_requests counter\nhttp_requests_total{method=\"GET\",path=\"/metrics\"} 1\n# EOF\n");
+
+        for (path, status) in [
+            ("/healthz", StatusCode::OK),
+            ("/readyz", StatusCode::SERVICE_UNAVAILABLE),
+            ("/", StatusCode::NOT_FOUND),
+            ("/metrics/service_map", StatusCode::NOT_FOUND),
+        ] {
+            let url = format!("http://{}{}", metrics_addr, path);
+            let resp = fetch_url(url.parse::<hyper::Uri>().unwrap()).await.unwrap();
+            assert_eq!(resp.status(), status, "{}", path);
+        }
         server_handle.abort();
     }
 }
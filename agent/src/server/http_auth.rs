@@ -0,0 +1,238 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use ahash::AHashMap;
+use hyper::StatusCode;
+use k8s_openapi::api::authentication::v1::{TokenReview, TokenReviewSpec};
+use k8s_openapi::api::authorization::v1::{
+    NonResourceAttributes, SubjectAccessReview, SubjectAccessReviewSpec,
+};
+use kube::api::PostParams;
+use kube::{Api, Client};
+use log::{debug, warn};
+use parking_lot::Mutex;
+use tokio_rustls::rustls::crypto::ring::default_provider;
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::TlsAcceptor;
+
+use crate::Args;
+
+/// How long the outcome of a token review is reused for the same token and path.
+const TOKEN_REVIEW_TTL: Duration = Duration::from_secs(60);
+
+/// TLS configuration of the metrics server.
+#[derive(Debug, Clone)]
+pub(crate) struct MetricsTlsConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: Option<PathBuf>,
+}
+
+impl MetricsTlsConfig {
+    /// Builds the TLS configuration, or `None` if the metrics server serves plain HTTP.
+    pub(crate) fn from_args(args: &Args) -> anyhow::Result<Option<Self>> {
+        match (args.metrics_tls_cert.as_ref(), args.metrics_tls_key.as_ref()) {
+            (Some(cert), Some(key)) => Ok(Some(Self {
+                cert: cert.clone(),
+                key: key.clone(),
+                client_ca: args.metrics_tls_client_ca.clone(),
+            })),
+            (None, None) if args.metrics_tls_client_ca.is_none() => Ok(None),
+            _ => Err(anyhow::anyhow!(
+                "--metrics-tls-cert and --metrics-tls-key must be set together, and are required by --metrics-tls-client-ca"
+            )),
+        }
+    }
+
+    /// Builds the acceptor of TLS connections. Client certificates are verified against
+    /// the client CA if one is configured, but not required at the TLS layer so that
+    /// probes can still reach `/healthz` and `/readyz`.
+    pub(crate) fn acceptor(&self) -> anyhow::Result<TlsAcceptor> {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(&self.cert)?))
+            .collect::<Result<Vec<_>, _>>()?;
+        let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&self.key)?))?
+            .ok_or_else(|| anyhow::anyhow!("No private key found in {}", self.key.display()))?;
+
+        let provider = Arc::new(default_provider());
+        let builder = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let builder = match self.client_ca.as_ref() {
+            Some(client_ca) => {
+                let verifier = WebPkiClientVerifier::builder_with_provider(
+                    Arc::new(load_roots(client_ca)?),
+                    provider,
+                )
+                .allow_unauthenticated()
+                .build()?;
+                builder.with_client_cert_verifier(verifier)
+            }
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_single_cert(certs, key)?;
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
+
+fn load_roots(path: &Path) -> anyhow::Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)) {
+        roots.add(cert?)?;
+    }
+    Ok(roots)
+}
+
+/// The token and path a review was made for.
+type ReviewKey = (String, String);
+
+/// Authorizes bearer tokens the way kube-rbac-proxy does: the token is authenticated
+/// with a TokenReview, and the resulting user must be allowed to `get` the requested
+/// path by a SubjectAccessReview.
+#[derive(Clone)]
+pub(crate) struct TokenAuthorizer {
+    client: Client,
+    reviews: Arc<Mutex<AHashMap<ReviewKey, (Instant, bool)>>>,
+}
+
+impl std::fmt::Debug for TokenAuthorizer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenAuthorizer").finish_non_exhaustive()
+    }
+}
+
+impl TokenAuthorizer {
+    pub(crate) async fn new() -> anyhow::Result<Self> {
+        Ok(Self {
+            client: Client::try_default().await?,
+            reviews: Arc::new(Mutex::new(AHashMap::new())),
+        })
+    }
+
+    /// Returns whether the bearer `token` may read `path`.
+    pub(crate) async fn authorize(&self, token: &str, path: &str) -> bool {
+        let key = (token.to_string(), path.to_string());
+        if let Some((reviewed_at, allowed)) = self.reviews.lock().get(&key) {
+            if reviewed_at.elapsed() < TOKEN_REVIEW_TTL {
+                return *allowed;
+            }
+        }
+
+        let allowed = match self.review(token, path).await {
+            Ok(allowed) => allowed,
+            Err(e) => {
+                // Do not cache failures of the API server.
+                warn!("Failed to review metrics token: {:?}", e);
+                return false;
+            }
+        };
+        let mut reviews = self.reviews.lock();
+        reviews.retain(|_, (reviewed_at, _)| reviewed_at.elapsed() < TOKEN_REVIEW_TTL);
+        reviews.insert(key, (Instant::now(), allowed));
+        allowed
+    }
+
+    async fn review(&self, token: &str, path: &str) -> anyhow::Result<bool> {
+        let token_review = TokenReview {
+            spec: TokenReviewSpec {
+                token: Some(token.to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let token_review = Api::<TokenReview>::all(self.client.clone())
+            .create(&PostParams::default(), &token_review)
+            .await?;
+        let status = match token_review.status {
+            Some(status) if status.authenticated == Some(true) => status,
+            _ => {
+                debug!("Metrics token is not authenticated");
+                return Ok(false);
+            }
+        };
+        let user = status.user.unwrap_or_default();
+
+        let access_review = SubjectAccessReview {
+            spec: SubjectAccessReviewSpec {
+                user: user.username.clone(),
+                uid: user.uid,
+                groups: user.groups,
+                extra: user.extra,
+                non_resource_attributes: Some(NonResourceAttributes {
+                    path: Some(path.to_string()),
+                    verb: Some("get".to_string()),
+                }),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let access_review = Api::<SubjectAccessReview>::all(self.client.clone())
+            .create(&PostParams::default(), &access_review)
+            .await?;
+        let allowed = access_review.status.is_some_and(|status| status.allowed);
+        if !allowed {
+            debug!("User {:?} may not get {}", user.username, path);
+        }
+        Ok(allowed)
+    }
+}
+
+/// Access control of the metrics endpoint. A request is allowed if it presented a
+/// verified client certificate, or otherwise carries a bearer token that passes the
+/// token authorizer. Without either mechanism configured, every request is allowed.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MetricsAuth {
+    pub require_client_cert: bool,
+    pub token_authorizer: Option<TokenAuthorizer>,
+}
+
+impl MetricsAuth {
+    pub(crate) async fn check(
+        &self,
+        authorization: Option<&str>,
+        client_verified: bool,
+        path: &str,
+    ) -> Result<(), StatusCode> {
+        if client_verified {
+            return Ok(());
+        }
+        match self.token_authorizer.as_ref() {
+            Some(authorizer) => {
+                let token = authorization
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .ok_or(StatusCode::UNAUTHORIZED)?;
+                if authorizer.authorize(token.trim(), path).await {
+                    Ok(())
+                } else {
+                    Err(StatusCode::FORBIDDEN)
+                }
+            }
+            None if self.require_client_cert => Err(StatusCode::UNAUTHORIZED),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_metrics_auth_without_token_authorizer() {
+        let open = MetricsAuth::default();
+        assert_eq!(open.check(None, false, "/metrics").await, Ok(()));
+
+        let mtls = MetricsAuth {
+            require_client_cert: true,
+            token_authorizer: None,
+        };
+        assert_eq!(mtls.check(None, true, "/metrics").await, Ok(()));
+        assert_eq!(
+            mtls.check(Some("Bearer token"), false, "/metrics").await,
+            Err(StatusCode::UNAUTHORIZED)
+        );
+    }
+}
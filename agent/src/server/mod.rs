@@ -7,34 +7,133 @@ use tokio::task::{JoinHandle, JoinSet};
 use agent_api::select_channel;
 use agent_api::v1::agent_server::AgentServer;
 
+use crate::collector::Collector;
+use crate::exporter::otlp::{OtlpConfig, OtlpExporter};
+use crate::exporter::remote_write::{RemoteWriteConfig, RemoteWriteExporter};
+use crate::managers::cardinality::CardinalityLimits;
+use crate::managers::metrics::MetricsManager;
 use crate::managers::prog::ProgManager;
 use crate::progs::types::ShutdownSignal;
 use crate::Args;
 
+pub(crate) mod audit;
+pub(crate) mod auth;
+pub(crate) mod health;
 pub(crate) mod http;
+pub(crate) mod http_auth;
 pub(crate) mod rpc;
 
 pub(crate) async fn serve(args: Args) -> anyhow::Result<()> {
     let (shutdown_tx, shutdown_rx1) = broadcast::channel(32);
     let shutdown_handle = tokio::spawn(shutdown_handler(shutdown_tx.clone()));
 
+    let tcp_config = rpc::TcpConfig::from_args(&args)?;
+    let metrics_tls_config = http_auth::MetricsTlsConfig::from_args(&args)?;
+    let metrics_auth = http_auth::MetricsAuth {
+        require_client_cert: args.metrics_tls_client_ca.is_some(),
+        token_authorizer: if args.metrics_token_auth {
+            Some(http_auth::TokenAuthorizer::new().await?)
+        } else {
+            None
+        },
+    };
+    let otlp_exporter = match OtlpConfig::from_args(&args)? {
+        Some(config) => Some(OtlpExporter::new(config)?),
+        None => None,
+    };
+    let remote_write_exporter = match RemoteWriteConfig::from_args(&args)? {
+        Some(config) => Some(RemoteWriteExporter::new(config)?),
+        None => None,
+    };
+    let policy = match args.agent_socket_policy.as_ref() {
+        Some(path) => Some(auth::Policy::load(path)?),
+        None => None,
+    };
+    let audit_log = audit::AuditLog::new(
+        args.audit_log_path.clone(),
+        args.audit_log_max_size,
+        args.audit_log_max_files,
+    )?;
+    let metrics_manager =
+        MetricsManager::new(args.metric_namespace, CardinalityLimits::from_args(&args)?);
     let channel = select_channel(args.bpfman_socket_path).unwrap();
     let bpf_client = BpfmanClient::new(channel);
-    let prog_manager = ProgManager::new(shutdown_tx.clone()).await?;
-    let agent_service = rpc::AgentService::new(prog_manager.clone(), bpf_client);
+    let prog_manager = ProgManager::new(shutdown_tx.clone(), metrics_manager).await?;
+    let (health_checker, health_service) =
+        health::HealthChecker::new(prog_manager.cache_manager.clone(), bpf_client.clone()).await;
+    let readiness = health_checker.readiness();
+    let agent_service = rpc::AgentService::new(
+        prog_manager.clone(),
+        bpf_client,
+        policy,
+        audit_log,
+        readiness.clone(),
+    );
     let service = AgentServer::new(agent_service);
 
     let mut listeners: Vec<_> = Vec::new();
-    let rpc_handler = rpc::serve(&args.agent_socket_path, service, shutdown_rx1).await?;
+    if let Some(tcp_config) = tcp_config {
+        let tcp_handler = rpc::serve_tcp(
+            tcp_config,
+            service.clone(),
+            health_service.clone(),
+            shutdown_tx.subscribe(),
+        )
+        .await?;
+        listeners.push(tcp_handler);
+    }
+    let rpc_handler = rpc::serve(
+        &args.agent_socket_path,
+        service,
+        health_service,
+        shutdown_rx1,
+    )
+    .await?;
     listeners.push(rpc_handler);
+    let health_shutdown_rx = shutdown_tx.subscribe();
+    listeners.push(tokio::spawn(async move {
+        health_checker.start(health_shutdown_rx).await;
+    }));
     let shutdown_rx2 = shutdown_tx.subscribe();
-    let http_server = http::serve(
-        args.metrics_addr,
+    let collector = Collector::new(
         prog_manager.registry_manager.clone(),
-        shutdown_rx2,
-    )
-    .await?;
+        prog_manager.map_manager.clone(),
+        prog_manager.cache_manager.clone(),
+        prog_manager.metrics_manager.clone(),
+    );
+    let router = http::Router::new(
+        args.metrics_path,
+        prog_manager.registry_manager.clone(),
+        prog_manager.metrics_manager.clone(),
+        collector.clone(),
+        readiness,
+        args.tolerated_failed_programs,
+        metrics_auth,
+    );
+    let http_server =
+        http::serve(args.metrics_addr, router, metrics_tls_config, shutdown_rx2).await?;
     listeners.push(http_server);
+    if let Some(remote_write_exporter) = remote_write_exporter {
+        let remote_write_shutdown_rx = shutdown_tx.subscribe();
+        let collector = collector.clone();
+        listeners.push(tokio::spawn(async move {
+            remote_write_exporter
+                .start(collector, remote_write_shutdown_rx)
+                .await;
+        }));
+    }
+    if let Some(otlp_exporter) = otlp_exporter {
+        let otlp_shutdown_rx = shutdown_tx.subscribe();
+        let collector = collector.clone();
+        listeners.push(tokio::spawn(async move {
+            otlp_exporter.start(collector, otlp_shutdown_rx).await;
+        }));
+    }
+    let shutdown_rx3 = shutdown_tx.subscribe();
+    let map_manager = prog_manager.map_manager.clone();
+    listeners.push(tokio::spawn(async move {
+        map_manager.start(shutdown_rx3).await;
+    }));
 
     let (_, res) = tokio::join!(join_listeners(listeners), shutdown_handle);
     if let Some(e) = res.err() {
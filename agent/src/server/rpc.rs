@@ -1,6 +1,9 @@
 use std::collections::HashMap;
-use std::fs::remove_file;
-use std::path::Path;
+use std::fs::{read, remove_file};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
 
 use bpfman_api::v1::bpfman_client::BpfmanClient;
 use bpfman_lib::utils::set_file_permissions;
@@ -9,45 +12,94 @@ use tokio::net::UnixListener;
 use tokio::sync::broadcast;
 use tokio::task::JoinHandle;
 use tokio_stream::wrappers::UnixListenerStream;
-use tonic::transport::{Channel, Server};
+use tonic::service::interceptor::InterceptedService;
+use tonic::transport::{Certificate, Channel, Identity, Server, ServerTlsConfig};
 use tonic::{Request, Response, Status};
+use tonic_health::pb::health_server::{Health, HealthServer};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
 
 use agent_api::v1::agent_server::{Agent, AgentServer};
+use agent_api::v1::dump_map_response::MapEntry;
 use agent_api::v1::list_response::ListResult;
 use agent_api::v1::{
-    GetRequest, GetResponse, ListRequest, ListResponse, LoadRequest, LoadResponse,
-    PullBytecodeRequest, PullBytecodeResponse, UnloadRequest, UnloadResponse,
+    DumpMapRequest, DumpMapResponse, GetAuditLogRequest, GetAuditLogResponse, GetRequest,
+    GetResponse, ListRequest, ListResponse, LoadRequest, LoadResponse, PullBytecodeRequest,
+    PullBytecodeResponse, UnloadRequest, UnloadResponse,
 };
 
+use crate::collector::agent::AGENT_METRICS;
 use crate::common::constants::directories::SOCK_MODE;
+use crate::common::error::AgentError;
 use crate::common::types::ListFilter;
 use crate::managers::prog::ProgManager;
 use crate::progs::types::ShutdownSignal;
+use crate::server::audit::{self, AuditLog};
+use crate::server::auth::Policy;
+use crate::server::health::{reflection_service, Readiness};
+use crate::Args;
 
 pub struct AgentService {
     pub prog_manager: ProgManager,
     pub bpf_client: BpfmanClient<Channel>,
+    pub policy: Option<Policy>,
+    pub(crate) audit_log: AuditLog,
+    pub(crate) readiness: Readiness,
 }
 
 impl AgentService {
-    pub(crate) fn new(prog_manager: ProgManager, bpf_client: BpfmanClient<Channel>) -> Self {
+    pub(crate) fn new(
+        prog_manager: ProgManager,
+        bpf_client: BpfmanClient<Channel>,
+        policy: Option<Policy>,
+        audit_log: AuditLog,
+        readiness: Readiness,
+    ) -> Self {
         Self {
             prog_manager,
             bpf_client,
+            policy,
+            audit_log,
+            readiness,
+        }
+    }
+
+    /// Programs resolve IPs through the Kubernetes cache, so they are not loaded or
+    /// unloaded before it has synced.
+    fn check_cache_synced(&self) -> Result<(), AgentError> {
+        if !self.readiness.cache_synced() {
+            return Err(AgentError::CacheNotSynced);
+        }
+        Ok(())
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn authorize<T>(&self, request: &Request<T>, method: &str) -> Result<(), Status> {
+        match self.policy.as_ref() {
+            Some(policy) => policy.authorize(request, method),
+            None => Ok(()),
         }
     }
 
     async fn get_prog_ids_for_maps(
         &self,
         map_to_prog_name: HashMap<String, String>,
-    ) -> Result<HashMap<String, u32>, anyhow::Error> {
+    ) -> Result<HashMap<String, u32>, AgentError> {
         let req = Request::new(bpfman_api::v1::ListRequest {
             program_type: None,
             bpfman_programs_only: None,
             match_metadata: Default::default(),
         });
         let mut bpf_client = self.bpf_client.clone();
-        let response = bpf_client.list(req).await?.into_inner();
+        let response = bpf_client
+            .list(req)
+            .await
+            .map_err(|e| {
+                AGENT_METRICS.record_bpfman_error("List");
+                AgentError::from(e)
+            })?
+            .into_inner();
         let loaded_ebpf_progs = response
             .results
             .iter()
@@ -57,37 +109,34 @@ impl AgentService {
 
         let mut map_to_prog_id = HashMap::new();
         for (map_name, prog_name) in map_to_prog_name {
-            let prog_id = loaded_ebpf_progs.get(&prog_name).ok_or(anyhow::anyhow!(
-                "Required eBPF program {} not loaded",
-                prog_name
-            ))?;
+            let prog_id = loaded_ebpf_progs.get(&prog_name).ok_or_else(|| {
+                AgentError::EbpfProgramNotLoaded {
+                    program: prog_name.clone(),
+                    map: map_name.clone(),
+                }
+            })?;
             map_to_prog_id.insert(map_name, *prog_id);
         }
         Ok(map_to_prog_id)
     }
-}
 
-#[tonic::async_trait]
-impl Agent for AgentService {
-    async fn load(&self, request: Request<LoadRequest>) -> Result<Response<LoadResponse>, Status> {
+    async fn load_program(
+        &self,
+        request: Request<LoadRequest>,
+    ) -> Result<Response<LoadResponse>, Status> {
+        self.authorize(&request, "Load")?;
+        self.check_cache_synced()?;
         let request = request.into_inner();
+        if request.name.is_empty() {
+            return Err(AgentError::InvalidArgument {
+                field: "name".to_string(),
+                reason: "must not be empty".to_string(),
+            }
+            .into());
+        }
 
-        let map_to_prog_id = self
-            .get_prog_ids_for_maps(request.ebpf_maps)
-            .await
-            .map_err(|e| {
-                Status::aborted(format!(
-                    "Failed to get eBPF program IDs: {:?}",
-                    e.to_string()
-                ))
-            })?;
-
-        let program_type = request.program_type.try_into().map_err(|_| {
-            Status::aborted(format!(
-                "Failed to convert program type: {:?}",
-                request.program_type
-            ))
-        })?;
+        let program_type = request.program_type.try_into().map_err(AgentError::from)?;
+        let map_to_prog_id = self.get_prog_ids_for_maps(request.ebpf_maps).await?;
 
         let prog = self
             .prog_manager
@@ -98,40 +147,33 @@ impl Agent for AgentService {
                 self.prog_manager.cache_manager.clone(),
                 map_to_prog_id,
             )
-            .await
-            .map_err(|e| {
-                Status::aborted(format!("Failed to pre-load program: {:?}", e.to_string()))
-            })?;
+            .await?;
 
-        self.prog_manager
-            .load(prog.clone())
-            .await
-            .map_err(|e| Status::aborted(format!("Failed to load program: {:?}", e.to_string())))?;
+        self.prog_manager.load(prog.clone()).await?;
 
-        let prog_info = prog.get_program_info().map_err(|e| {
-            Status::aborted(format!("Failed to get program info: {:?}", e.to_string()))
-        })?;
+        let prog_info = prog.get_program_info().map_err(AgentError::from)?;
 
         Ok(Response::new(LoadResponse {
             info: Some(prog_info),
         }))
     }
 
-    async fn unload(
+    async fn unload_program(
         &self,
         request: Request<UnloadRequest>,
     ) -> Result<Response<UnloadResponse>, Status> {
+        self.authorize(&request, "Unload")?;
+        self.check_cache_synced()?;
         let request = request.into_inner();
-        self.prog_manager
-            .unload(request.name.clone())
-            .await
-            .map_err(|e| {
-                Status::aborted(format!("Failed to unload program: {:?}", e.to_string()))
-            })?;
+        self.prog_manager.unload(request.name.clone()).await?;
         Ok(Response::new(UnloadResponse {}))
     }
 
-    async fn list(&self, request: Request<ListRequest>) -> Result<Response<ListResponse>, Status> {
+    async fn list_programs(
+        &self,
+        request: Request<ListRequest>,
+    ) -> Result<Response<ListResponse>, Status> {
+        self.authorize(&request, "List")?;
         let request = request.into_inner();
         let list_filter = ListFilter::new(request.program_type, request.match_metadata.clone());
 
@@ -141,9 +183,7 @@ impl Agent for AgentService {
 
         for prog in progs.iter() {
             let reply_entry = ListResult {
-                info: Some(prog.get_program_info().map_err(|e| {
-                    Status::aborted(format!("Failed to get program info: {:?}", e.to_string()))
-                })?),
+                info: Some(prog.get_program_info().map_err(AgentError::from)?),
             };
             reply.results.push(reply_entry);
         }
@@ -151,35 +191,160 @@ impl Agent for AgentService {
         Ok(Response::new(reply))
     }
 
-    async fn pull_bytecode(
+    async fn get_program(
         &self,
-        _request: Request<PullBytecodeRequest>,
-    ) -> Result<Response<PullBytecodeResponse>, Status> {
-        todo!()
-    }
-
-    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        request: Request<GetRequest>,
+    ) -> Result<Response<GetResponse>, Status> {
+        self.authorize(&request, "Get")?;
         let request = request.into_inner();
         let prog = self
             .prog_manager
             .get(request.name.clone(), None)
             .await
-            .ok_or_else(|| Status::aborted(format!("Program {} not found", request.name)))?;
+            .ok_or_else(|| AgentError::ProgramNotFound {
+                name: request.name.clone(),
+            })?;
 
-        let prog_info = prog.get_program_info().map_err(|e| {
-            Status::aborted(format!("Failed to get program info: {:?}", e.to_string()))
-        })?;
+        let prog_info = prog.get_program_info().map_err(AgentError::from)?;
 
         Ok(Response::new(GetResponse {
             info: Some(prog_info),
         }))
     }
+
+    async fn dump_program_map(
+        &self,
+        request: Request<DumpMapRequest>,
+    ) -> Result<Response<DumpMapResponse>, Status> {
+        self.authorize(&request, "DumpMap")?;
+        let request = request.into_inner();
+        let entries = self
+            .prog_manager
+            .dump_map(
+                request.program,
+                request.map,
+                request.limit.map(|l| l as usize),
+            )
+            .await?;
+
+        Ok(Response::new(DumpMapResponse {
+            entries: entries
+                .into_iter()
+                .map(|(key, value)| MapEntry { key, value })
+                .collect(),
+        }))
+    }
+
+    async fn read_audit_log(
+        &self,
+        request: Request<GetAuditLogRequest>,
+    ) -> Result<Response<GetAuditLogResponse>, Status> {
+        self.authorize(&request, "GetAuditLog")?;
+        let request = request.into_inner();
+        let entries = self
+            .audit_log
+            .tail(request.limit.unwrap_or(100) as usize)
+            .map_err(|e| {
+                AgentError::from(anyhow::Error::new(e).context("Failed to read audit log"))
+            })?;
+
+        Ok(Response::new(GetAuditLogResponse { entries }))
+    }
+}
+
+#[tonic::async_trait]
+impl Agent for AgentService {
+    async fn load(&self, request: Request<LoadRequest>) -> Result<Response<LoadResponse>, Status> {
+        let peer = audit::peer(&request);
+        let params = audit::load_params(request.get_ref());
+        let result = observe("Load", self.load_program(request)).await;
+        self.audit_log.record("Load", peer, params, &result);
+        result
+    }
+
+    async fn unload(
+        &self,
+        request: Request<UnloadRequest>,
+    ) -> Result<Response<UnloadResponse>, Status> {
+        let peer = audit::peer(&request);
+        let params = audit::unload_params(request.get_ref());
+        let result = observe("Unload", self.unload_program(request)).await;
+        self.audit_log.record("Unload", peer, params, &result);
+        result
+    }
+
+    async fn list(&self, request: Request<ListRequest>) -> Result<Response<ListResponse>, Status> {
+        observe("List", self.list_programs(request)).await
+    }
+
+    async fn pull_bytecode(
+        &self,
+        request: Request<PullBytecodeRequest>,
+    ) -> Result<Response<PullBytecodeResponse>, Status> {
+        let peer = audit::peer(&request);
+        let params = audit::pull_bytecode_params(request.get_ref());
+        let result = observe("PullBytecode", async {
+            self.authorize(&request, "PullBytecode")
+                .and(Err(Status::unimplemented(
+                    "Pulling bytecode images is not supported yet",
+                )))
+        })
+        .await;
+        self.audit_log.record("PullBytecode", peer, params, &result);
+        result
+    }
+
+    async fn get(&self, request: Request<GetRequest>) -> Result<Response<GetResponse>, Status> {
+        observe("Get", self.get_program(request)).await
+    }
+
+    async fn dump_map(
+        &self,
+        request: Request<DumpMapRequest>,
+    ) -> Result<Response<DumpMapResponse>, Status> {
+        observe("DumpMap", self.dump_program_map(request)).await
+    }
+
+    async fn get_audit_log(
+        &self,
+        request: Request<GetAuditLogRequest>,
+    ) -> Result<Response<GetAuditLogResponse>, Status> {
+        observe("GetAuditLog", self.read_audit_log(request)).await
+    }
+}
+
+/// Records the request count and latency of an RPC handled by `handler`.
+async fn observe<T>(
+    method: &str,
+    handler: impl Future<Output = Result<T, Status>>,
+) -> Result<T, Status> {
+    let start = Instant::now();
+    let result = handler.await;
+    AGENT_METRICS.record_rpc(method, start.elapsed(), &result);
+    result
+}
+
+async fn wait_for_shutdown(listener: &str, mut shutdown_rx: broadcast::Receiver<ShutdownSignal>) {
+    loop {
+        match shutdown_rx.recv().await {
+            Ok(ShutdownSignal::All) => {
+                debug!("{}: Received shutdown signal", listener);
+                break;
+            }
+            Err(e) => {
+                error!("Error receiving shutdown signal {:?}", e.to_string());
+                continue;
+            }
+            _ => continue,
+        }
+    }
 }
 
 pub async fn serve(
     path: &Path,
     service: AgentServer<AgentService>,
-    mut shutdown_rx: broadcast::Receiver<ShutdownSignal>,
+    health_service: HealthServer<impl Health>,
+    shutdown_rx: broadcast::Receiver<ShutdownSignal>,
 ) -> anyhow::Result<JoinHandle<()>> {
     // Listen on Unix socket
     if path.exists() {
@@ -193,22 +358,10 @@ pub async fn serve(
     set_file_permissions(path, SOCK_MODE);
 
     let serve = Server::builder()
+        .add_service(health_service)
+        .add_service(reflection_service()?)
         .add_service(service)
-        .serve_with_incoming_shutdown(uds_stream, async move {
-            loop {
-                match shutdown_rx.recv().await {
-                    Ok(ShutdownSignal::All) => {
-                        debug!("Unix Socket: Received shutdown signal");
-                        break;
-                    }
-                    Err(e) => {
-                        error!("Error receiving shutdown signal {:?}", e.to_string());
-                        continue;
-                    }
-                    _ => continue,
-                }
-            }
-        });
+        .serve_with_incoming_shutdown(uds_stream, wait_for_shutdown("Unix Socket", shutdown_rx));
 
     let socket_path = path.to_path_buf();
     Ok(tokio::spawn(async move {
@@ -219,3 +372,127 @@ pub async fn serve(
         info!("Shutdown Unix Handler {}", socket_path.display());
     }))
 }
+
+/// Configuration of the mutual TLS TCP listener of the agent gRPC API.
+#[derive(Debug, Clone)]
+pub(crate) struct TcpConfig {
+    pub addr: SocketAddr,
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    pub client_ca: PathBuf,
+    pub allowed_sans: Vec<String>,
+}
+
+impl TcpConfig {
+    /// Builds the TCP listener configuration, or `None` if no TCP address is configured.
+    pub(crate) fn from_args(args: &Args) -> anyhow::Result<Option<Self>> {
+        let addr = match args.agent_tcp_addr.as_ref() {
+            Some(addr) => addr.parse::<SocketAddr>()?,
+            None => return Ok(None),
+        };
+        let missing = |flag: &str| anyhow::anyhow!("--agent-tcp-addr requires {}", flag);
+        Ok(Some(Self {
+            addr,
+            cert: args
+                .agent_tls_cert
+                .clone()
+                .ok_or_else(|| missing("--agent-tls-cert"))?,
+            key: args
+                .agent_tls_key
+                .clone()
+                .ok_or_else(|| missing("--agent-tls-key"))?,
+            client_ca: args
+                .agent_tls_client_ca
+                .clone()
+                .ok_or_else(|| missing("--agent-tls-client-ca"))?,
+            allowed_sans: args.agent_tls_allowed_sans.clone(),
+        }))
+    }
+}
+
+/// Returns the DNS, URI and IP subject alternative names of a DER encoded certificate.
+pub(crate) fn cert_sans(der: &[u8]) -> Vec<String> {
+    let cert = match X509Certificate::from_der(der) {
+        Ok((_, cert)) => cert,
+        Err(e) => {
+            debug!("Failed to parse client certificate: {:?}", e);
+            return vec![];
+        }
+    };
+    let sans = match cert.subject_alternative_name() {
+        Ok(Some(sans)) => sans,
+        _ => return vec![],
+    };
+    sans.value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(dns) => Some(dns.to_string()),
+            GeneralName::URI(uri) => Some(uri.to_string()),
+            GeneralName::IPAddress(ip) => match ip.len() {
+                4 => Some(IpAddr::from(<[u8; 4]>::try_from(*ip).ok()?).to_string()),
+                16 => Some(IpAddr::from(<[u8; 16]>::try_from(*ip).ok()?).to_string()),
+                _ => None,
+            },
+            _ => None,
+        })
+        .collect()
+}
+
+#[allow(clippy::result_large_err)]
+fn check_peer_sans(request: Request<()>, allowed_sans: &[String]) -> Result<Request<()>, Status> {
+    if allowed_sans.is_empty() {
+        return Ok(request);
+    }
+    let certs = request
+        .peer_certs()
+        .ok_or_else(|| Status::unauthenticated("No client certificate presented"))?;
+    let sans = certs
+        .first()
+        .map(|cert| cert_sans(cert.get_ref()))
+        .unwrap_or_default();
+    if sans.iter().any(|san| allowed_sans.contains(san)) {
+        Ok(request)
+    } else {
+        Err(Status::permission_denied(format!(
+            "Client certificate names {:?} are not allowed",
+            sans
+        )))
+    }
+}
+
+pub async fn serve_tcp(
+    config: TcpConfig,
+    service: AgentServer<AgentService>,
+    health_service: HealthServer<impl Health>,
+    shutdown_rx: broadcast::Receiver<ShutdownSignal>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let identity = Identity::from_pem(read(&config.cert)?, read(&config.key)?);
+    let client_ca = Certificate::from_pem(read(&config.client_ca)?);
+    let tls_config = ServerTlsConfig::new()
+        .identity(identity)
+        .client_ca_root(client_ca);
+
+    let allowed_sans = config.allowed_sans.clone();
+    // The interceptor has to return a `Status`.
+    #[allow(clippy::result_large_err)]
+    let service = InterceptedService::new(service, move |request| {
+        check_peer_sans(request, &allowed_sans)
+    });
+
+    let serve = Server::builder()
+        .tls_config(tls_config)?
+        .add_service(health_service)
+        .add_service(reflection_service()?)
+        .add_service(service)
+        .serve_with_shutdown(config.addr, wait_for_shutdown("TCP Socket", shutdown_rx));
+
+    let addr = config.addr;
+    Ok(tokio::spawn(async move {
+        info!("Listening on {} (mTLS)", addr);
+        if let Err(e) = serve.await {
+            error!("Server error: {e:?}");
+        }
+        info!("Shutdown TCP Handler {}", addr);
+    }))
+}
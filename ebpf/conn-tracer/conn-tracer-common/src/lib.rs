@@ -17,6 +17,7 @@ pub const TCP_NEW_SYN_RECV: i32 = 12;
 pub const TCP_MAX_STATES: i32 = 13;
 
 pub const INET_SOCK_SKADDR_OFFSET: usize = 8;
+pub const INET_SOCK_OLDSTATE_OFFSET: usize = 16;
 pub const INET_SOCK_NEWSTATE_OFFSET: usize = 20;
 
 pub const CONNECTION_ROLE_UNKNOWN: u32 = 0;
@@ -30,23 +31,58 @@ pub struct SockInfo {
     pub pid: u32,
     pub is_active: u32,
     pub role: u32,
+    /// Time the connect started, for client sockets.
+    pub start_ns: u64,
+    /// Time from `SYN_SENT` to `ESTABLISHED`, once established.
+    pub handshake_ns: u64,
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for SockInfo {}
 
-#[derive(Copy, Clone, Debug, Default)]
+/// Addresses are kept in network byte order. IPv4 addresses, including those of
+/// IPv4-mapped IPv6 sockets, are stored as IPv4-mapped IPv6 addresses with family
+/// `AF_INET`.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
 #[repr(C)]
 pub struct ConnectionKey {
     pub id: u32,
     pub pid: u32,
-    pub src_addr: u32,
+    pub family: u32,
+    pub src_addr: [u8; 16],
     pub src_port: u32,
-    pub dest_addr: u32,
+    pub dest_addr: [u8; 16],
     pub dest_port: u32,
     pub role: u32,
 }
 
+impl ConnectionKey {
+    pub fn has_dest_addr(&self) -> bool {
+        let unspecified = match self.family as u16 {
+            AF_INET => ipv4_mapped(0),
+            _ => [0; 16],
+        };
+        // Compared byte by byte, as eBPF programs can't call memcmp.
+        self.dest_addr
+            .iter()
+            .zip(unspecified.iter())
+            .any(|(a, b)| a != b)
+    }
+}
+
+/// Returns the IPv4-mapped IPv6 form of an IPv4 address in network byte order.
+pub fn ipv4_mapped(addr: u32) -> [u8; 16] {
+    let octets = addr.to_be_bytes();
+    [
+        0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, octets[0], octets[1], octets[2], octets[3],
+    ]
+}
+
+/// Returns whether an IPv6 address is an IPv4-mapped address (`::ffff:a.b.c.d`).
+pub fn is_ipv4_mapped(addr: &[u8; 16]) -> bool {
+    addr[..10].iter().all(|b| *b == 0) && addr[10] == 0xff && addr[11] == 0xff
+}
+
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for ConnectionKey {}
 
@@ -56,6 +92,13 @@ pub struct ConnectionStats {
     pub bytes_sent: u64,
     pub bytes_received: u64,
     pub is_active: u64,
+    /// Smoothed RTT in microseconds, shifted left by 3 as kept by the kernel.
+    pub srtt_us: u64,
+    pub total_retrans: u64,
+    pub lost_out: u64,
+    pub handshake_ns: u64,
+    /// Set when the connection was closed before it was established.
+    pub connect_failed: u64,
 }
 
 #[cfg(feature = "user")]
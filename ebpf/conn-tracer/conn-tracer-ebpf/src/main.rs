@@ -7,9 +7,10 @@ use aya_ebpf::{
     programs::{ProbeContext, TracePointContext},
 };
 use conn_tracer_common::{
-    ConnectionKey, ConnectionStats, SockInfo, AF_INET, AF_INET6, CONNECTION_ROLE_CLIENT,
-    CONNECTION_ROLE_SERVER, CONNECTION_ROLE_UNKNOWN, INET_SOCK_NEWSTATE_OFFSET,
-    INET_SOCK_SKADDR_OFFSET, MAX_CONNECTIONS, TCP_CLOSE, TCP_SYN_RECV, TCP_SYN_SENT,
+    ipv4_mapped, is_ipv4_mapped, ConnectionKey, ConnectionStats, SockInfo, AF_INET, AF_INET6,
+    CONNECTION_ROLE_CLIENT, CONNECTION_ROLE_SERVER, CONNECTION_ROLE_UNKNOWN,
+    INET_SOCK_NEWSTATE_OFFSET, INET_SOCK_OLDSTATE_OFFSET, INET_SOCK_SKADDR_OFFSET, MAX_CONNECTIONS,
+    TCP_CLOSE, TCP_ESTABLISHED, TCP_SYN_RECV, TCP_SYN_SENT,
 };
 use vmlinux::{sock, sock_common, tcp_sock};
 
@@ -46,7 +47,7 @@ fn try_sock_conn_tracer(ctx: ProbeContext) -> Result<u32, i64> {
 
     parse_sock_data(sk, &mut conn_key, &mut conn_stats)?;
 
-    if conn_key.dest_addr == 0 && conn_key.dest_port == 0 {
+    if !conn_key.has_dest_addr() && conn_key.dest_port == 0 {
         return Ok(0);
     }
 
@@ -59,6 +60,7 @@ fn try_sock_conn_tracer(ctx: ProbeContext) -> Result<u32, i64> {
                 return Err(1i64);
             }
             conn_stats.is_active = sock_info.is_active as u64;
+            conn_stats.handshake_ns = sock_info.handshake_ns;
             unsafe {
                 CONNECTIONS.insert(&conn_key, &conn_stats, 0_u64)?;
             }
@@ -69,6 +71,8 @@ fn try_sock_conn_tracer(ctx: ProbeContext) -> Result<u32, i64> {
                 pid: 0,
                 is_active: 1,
                 role: get_sock_role(sk),
+                start_ns: 0,
+                handshake_ns: 0,
             };
 
             unsafe {
@@ -105,24 +109,44 @@ fn parse_sock_data(
     conn_stats.bytes_received =
         unsafe { bpf_probe_read_kernel(&(*tcp_sk).bytes_received as *const u64).map_err(|e| e)? };
 
+    // read link health data
+    conn_stats.srtt_us =
+        unsafe { bpf_probe_read_kernel(&(*tcp_sk).srtt_us as *const u32).map_err(|e| e)? } as u64;
+    conn_stats.total_retrans =
+        unsafe { bpf_probe_read_kernel(&(*tcp_sk).total_retrans as *const u32).map_err(|e| e)? }
+            as u64;
+    conn_stats.lost_out =
+        unsafe { bpf_probe_read_kernel(&(*tcp_sk).lost_out as *const u32).map_err(|e| e)? } as u64;
+
     // read connection data
+    let src_port = u16::from_be(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_num });
+    let dest_port = u16::from_be(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_dport });
+    conn_key.src_port = src_port as u32;
+    conn_key.dest_port = dest_port as u32;
     match sk_common.skc_family {
         AF_INET => {
             let src_addr =
                 u32::from_be(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_rcv_saddr });
             let dest_addr: u32 =
                 u32::from_be(unsafe { sk_common.__bindgen_anon_1.__bindgen_anon_1.skc_daddr });
-            let src_port =
-                u16::from_be(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_num });
-            let dest_port =
-                u16::from_be(unsafe { sk_common.__bindgen_anon_3.__bindgen_anon_1.skc_dport });
+            conn_key.family = AF_INET as u32;
+            conn_key.src_addr = ipv4_mapped(src_addr);
+            conn_key.dest_addr = ipv4_mapped(dest_addr);
+            Ok(0)
+        }
+        AF_INET6 => {
+            let src_addr = unsafe { sk_common.skc_v6_rcv_saddr.in6_u.u6_addr8 };
+            let dest_addr = unsafe { sk_common.skc_v6_daddr.in6_u.u6_addr8 };
+            // Dual-stack sockets carry IPv4 peers as IPv4-mapped addresses.
+            conn_key.family = if is_ipv4_mapped(&dest_addr) {
+                AF_INET as u32
+            } else {
+                AF_INET6 as u32
+            };
             conn_key.src_addr = src_addr;
             conn_key.dest_addr = dest_addr;
-            conn_key.src_port = src_port as u32;
-            conn_key.dest_port = dest_port as u32;
             Ok(0)
         }
-        AF_INET6 => Err(1i64),
         _ => Err(1i64),
     }
 }
@@ -158,12 +182,14 @@ pub fn sock_state_tracer(ctx: TracePointContext) -> u32 {
 
 fn try_state_tracer(ctx: TracePointContext) -> Result<u32, i64> {
     let sk: *const sock = unsafe { ctx.read_at::<*const sock>(INET_SOCK_SKADDR_OFFSET)? };
+    let old_state: i32 = unsafe { ctx.read_at::<i32>(INET_SOCK_OLDSTATE_OFFSET)? };
     let new_state: i32 = unsafe { ctx.read_at::<i32>(INET_SOCK_NEWSTATE_OFFSET)? };
 
     match new_state {
         TCP_SYN_RECV => handle_tcp_syn_recv(sk),
         TCP_SYN_SENT => handle_tcp_syn_sent(sk),
-        TCP_CLOSE => handle_tcp_close(sk),
+        TCP_ESTABLISHED => handle_tcp_established(sk),
+        TCP_CLOSE => handle_tcp_close(sk, old_state),
         _ => Ok(0),
     }
 }
@@ -176,6 +202,8 @@ fn handle_tcp_syn_sent(sk: *const sock) -> Result<u32, i64> {
         pid,
         is_active: 1,
         role: CONNECTION_ROLE_CLIENT,
+        start_ns: unsafe { bpf_ktime_get_ns() },
+        handshake_ns: 0,
     };
 
     unsafe {
@@ -185,6 +213,42 @@ fn handle_tcp_syn_sent(sk: *const sock) -> Result<u32, i64> {
     Ok(0)
 }
 
+/// Records connections as soon as they are established, so that connections which
+/// never carry data are still counted as open. For client sockets the time since
+/// `SYN_SENT` is kept as the handshake latency; on the server side the socket only
+/// exists once the handshake is done.
+fn handle_tcp_established(sk: *const sock) -> Result<u32, i64> {
+    let Some(mut sock_info) = (unsafe { SOCKETS.get(&sk) }).copied() else {
+        return Ok(0);
+    };
+    if sock_info.start_ns != 0 {
+        sock_info.handshake_ns = unsafe { bpf_ktime_get_ns() } - sock_info.start_ns;
+        unsafe {
+            SOCKETS.insert(&sk, &sock_info, 0_u64)?;
+        }
+    }
+    let mut conn_key = ConnectionKey::default();
+    let mut conn_stats = ConnectionStats::default();
+
+    parse_sock_data(sk, &mut conn_key, &mut conn_stats)?;
+
+    if !conn_key.has_dest_addr() {
+        return Ok(0);
+    }
+
+    conn_key.id = sock_info.id;
+    conn_key.pid = sock_info.pid;
+    conn_key.role = sock_info.role;
+    conn_stats.is_active = 1;
+    conn_stats.handshake_ns = sock_info.handshake_ns;
+
+    unsafe {
+        CONNECTIONS.insert(&conn_key, &conn_stats, 0_u64)?;
+    }
+
+    Ok(0)
+}
+
 fn handle_tcp_syn_recv(sk: *const sock) -> Result<u32, i64> {
     let mut conn_key = ConnectionKey::default();
     let mut conn_stats = ConnectionStats::default();
@@ -196,13 +260,15 @@ fn handle_tcp_syn_recv(sk: *const sock) -> Result<u32, i64> {
         pid: 0,
         is_active: 1,
         role: CONNECTION_ROLE_SERVER,
+        start_ns: 0,
+        handshake_ns: 0,
     };
 
     unsafe {
         SOCKETS.insert(&sk, &sock_info, 0_u64)?;
     }
 
-    if conn_key.dest_addr == 0 {
+    if !conn_key.has_dest_addr() {
         return Ok(0);
     }
 
@@ -217,16 +283,22 @@ fn handle_tcp_syn_recv(sk: *const sock) -> Result<u32, i64> {
     Ok(0)
 }
 
-fn handle_tcp_close(sk: *const sock) -> Result<u32, i64> {
+fn handle_tcp_close(sk: *const sock, old_state: i32) -> Result<u32, i64> {
     let mut conn_key = ConnectionKey::default();
     let mut conn_stats = ConnectionStats::default();
 
     parse_sock_data(sk, &mut conn_key, &mut conn_stats)?;
 
+    // Connects that were refused or timed out go straight from SYN_SENT to CLOSE.
+    if old_state == TCP_SYN_SENT {
+        conn_stats.connect_failed = 1;
+    }
+
     if let Some(sock_info) = unsafe { SOCKETS.get(&sk) } {
         conn_key.id = sock_info.id;
         conn_key.pid = sock_info.pid;
         conn_key.role = sock_info.role;
+        conn_stats.handshake_ns = sock_info.handshake_ns;
         unsafe {
             SOCKETS.remove(&sk)?;
         }
@@ -34,6 +34,7 @@ fn build_agent(_opts: &Options) -> anyhow::Result<()> {
     let protos = &["agent.proto"];
     let includes = &[proto_dir.to_str().unwrap()];
     tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("agent_descriptor.bin"))
         .out_dir(out_dir)
         .compile(protos, includes)?;
     Ok(())